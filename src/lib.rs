@@ -65,22 +65,65 @@
     html_logo_url = "https://raw.githubusercontent.com/smol-rs/smol/master/assets/images/logo_fullsize_transparent.png"
 )]
 
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::error;
 use std::fmt;
+use std::hash::Hash;
+use std::io;
 use std::iter;
-use std::panic;
+use std::mem;
+use std::ops::Range;
+use std::panic::{self, Location};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::thread;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, LocalKey};
+use std::time::{Duration, Instant};
+use std::vec;
 
 /// A builder that runs closures in parallel.
+///
+/// On targets without thread support, such as WebAssembly without the `atomics` feature, closures
+/// run one after another on the current thread instead, and results and panics are handled the
+/// same way. WebAssembly targets with `atomics`, like `wasm32-wasip1-threads`, spawn real threads
+/// if the runtime supports the wasi-threads proposal. Likewise, if a thread cannot be spawned
+/// because the system is out of resources, the closures that didn't get a thread run on the
+/// current thread.
+///
+/// Dropping a builder discards its closures without running them. Since the builder is
+/// `#[must_use]`, the compiler warns about a batch that is built but never used.
+///
+/// When a closure panics, the panic resumes in the main thread with its original payload. The
+/// panic hook runs while the closure is still the current task, so a hook can find out where the
+/// panicking closure was added with [`TaskInfo::current()`] and [`TaskInfo::location()`].
 #[must_use]
 pub struct Parallel<'a, T> {
     /// Closures to run.
     closures: Vec<Box<dyn FnOnce() -> T + Send + 'a>>,
+
+    /// Settings for running the closures.
+    config: ParallelConfig,
+
+    /// Called on the main thread with each result as it is collected.
+    inspect: Option<Inspect<'a, T>>,
+
+    /// When [`Parallel::run_step()`] was first called and how many closures it has run since.
+    stepped: Option<(Instant, usize)>,
+
+    /// For each closure, the number of the `add()` or `each()` call that added it.
+    sources: Vec<usize>,
 }
 
+/// A hook called with the index and the result of a closure, set by [`Parallel::inspect()`].
+type Inspect<'a, T> = Arc<dyn Fn(usize, &T) + Send + Sync + 'a>;
+
 impl<'a, T> Parallel<'a, T> {
     /// Creates a builder for running closures in parallel.
     ///
+    /// The builder starts with the settings passed to [`set_default_config()`], if any.
+    ///
     /// # Examples
     ///
     /// ```
@@ -91,6 +134,10 @@ impl<'a, T> Parallel<'a, T> {
     pub fn new() -> Parallel<'a, T> {
         Parallel {
             closures: Vec::new(),
+            config: ParallelConfig::global(),
+            inspect: None,
+            stepped: None,
+            sources: Vec::new(),
         }
     }
 
@@ -106,15 +153,136 @@ impl<'a, T> Parallel<'a, T> {
     ///     .run();
     /// ```
     #[allow(clippy::should_implement_trait)]
+    #[track_caller]
     pub fn add<F>(mut self, f: F) -> Parallel<'a, T>
     where
         F: FnOnce() -> T + Send + 'a,
         T: Send + 'a,
     {
-        self.closures.push(Box::new(f));
+        let location = Location::caller();
+        let source = self.next_source();
+        self.closures
+            .push(Box::new(move || at_location(location, f)));
+        self.sources.push(source);
         self
     }
 
+    /// Adds a closure to the list only if a condition is true.
+    ///
+    /// This keeps builder chains intact when some closures are optional.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let verbose = false;
+    ///
+    /// let res = Parallel::new()
+    ///     .add(|| "compile")
+    ///     .add_if(verbose, || "log")
+    ///     .add_if(!verbose, || "summary")
+    ///     .run();
+    ///
+    /// assert_eq!(res, ["compile", "summary"]);
+    /// ```
+    #[track_caller]
+    pub fn add_if<F>(self, condition: bool, f: F) -> Parallel<'a, T>
+    where
+        F: FnOnce() -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        if condition {
+            self.add(f)
+        } else {
+            self
+        }
+    }
+
+    /// Adds a closure that receives information about its task.
+    ///
+    /// The [`TaskInfo`] argument carries the index of the closure in the batch and the total number
+    /// of closures, which is useful for partitioning work by index or for diagnostics.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .add_with_info(|info| format!("{}/{}", info.index(), info.total()))
+    ///     .add_with_info(|info| format!("{}/{}", info.index(), info.total()))
+    ///     .run();
+    ///
+    /// assert_eq!(res, ["0/2", "1/2"]);
+    /// ```
+    #[track_caller]
+    pub fn add_with_info<F>(self, f: F) -> Parallel<'a, T>
+    where
+        F: FnOnce(TaskInfo) -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        self.add(move || f(TaskInfo::current().expect("closure must run as a task")))
+    }
+
+    /// Adds a closure that always runs on a newly spawned thread.
+    ///
+    /// Other closures may run on the main thread or, if limits set with [`ParallelConfig`] are
+    /// reached, on the thread that runs the batch. This closure never does, which is useful when
+    /// it relies on a pristine thread, like fresh thread-local variables. Only the information
+    /// about the task, like [`current_task_index()`], carries over to the new thread.
+    ///
+    /// The new thread uses the process-wide default settings from [`set_default_config()`], since
+    /// the settings of the batch are not known when the closure is added. If those settings don't
+    /// allow another thread, the thread cannot be spawned, or the target doesn't support threads,
+    /// the closure runs like all others.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    ///
+    /// let main = thread::current().id();
+    ///
+    /// let res = Parallel::new()
+    ///     .add_isolated(|| thread::current().id())
+    ///     .run();
+    ///
+    /// assert_ne!(res[0], main);
+    /// ```
+    #[track_caller]
+    pub fn add_isolated<F>(self, f: F) -> Parallel<'a, T>
+    where
+        F: FnOnce() -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        self.add(move || {
+            if !THREADS {
+                return f();
+            }
+
+            let config = ParallelConfig::global();
+            let cx = Context::capture();
+            let task = TaskInfo::current();
+            thread::scope(|scope| {
+                let isolated = {
+                    let cx = cx.clone();
+                    move || {
+                        CURRENT_TASK.with(|c| c.set(task));
+                        cx.resume(f)
+                    }
+                };
+                match config.spawn_scoped(scope, &cx, isolated) {
+                    Ok(handle) => handle
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err)),
+                    Err(isolated) => isolated(),
+                }
+            })
+        })
+    }
+
     /// Adds a cloned closure for each item in an iterator.
     ///
     /// Each clone of the closure takes an item as an argument.
@@ -128,6 +296,21 @@ impl<'a, T> Parallel<'a, T> {
     ///     .each(0..5, |i| println!("hello from thread #{}", i))
     ///     .run();
     /// ```
+    ///
+    /// Any iterator works, including iterators over overlapping windows of a slice:
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let signal = [1, 3, 5, 7, 9];
+    ///
+    /// let moving_sums = Parallel::new()
+    ///     .each(signal.windows(3), |w| w.iter().sum::<i32>())
+    ///     .run();
+    ///
+    /// assert_eq!(moving_sums, [9, 15, 21]);
+    /// ```
+    #[track_caller]
     pub fn each<A, I, F>(mut self, iter: I, f: F) -> Parallel<'a, T>
     where
         I: IntoIterator<Item = A>,
@@ -135,189 +318,5791 @@ impl<'a, T> Parallel<'a, T> {
         A: Send + 'a,
         T: Send + 'a,
     {
-        for t in iter.into_iter() {
+        let location = Location::caller();
+        let source = self.next_source();
+        let iter = iter.into_iter();
+        self.closures.reserve(iter.size_hint().0);
+        for t in iter {
             let f = f.clone();
-            self.closures.push(Box::new(|| f(t)));
+            self.closures
+                .push(Box::new(move || at_location(location, || f(t))));
+            self.sources.push(source);
         }
         self
     }
 
-    /// Runs each closure on a separate thread and collects their results.
-    ///
-    /// Results are collected in the order in which closures were added. One of the closures always
-    /// runs on the main thread because there is no point in spawning an extra thread for it.
+    /// Adds a cloned closure for each item in a fallible iterator.
     ///
-    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    /// This is the same as [`each()`][`Parallel::each()`], except that the iterator yields
+    /// [`Result`]s, like [`std::fs::read_dir()`] does. Building the batch stops at the first error,
+    /// which is returned. In that case, the batch is dropped and none of its closures run,
+    /// including those added before this call.
     ///
     /// # Examples
     ///
     /// ```
     /// use easy_parallel::Parallel;
-    /// use std::thread;
-    /// use std::time::Duration;
     ///
-    /// let res = Parallel::new()
-    ///     .each(1..=3, |i| 10 * i)
-    ///     .add(|| 100)
-    ///     .collect::<Vec<_>>();
+    /// let lens = Parallel::new()
+    ///     .try_each(["a", "bc", "def"].iter().map(Ok::<_, ()>), |s| s.len())?
+    ///     .run();
+    /// assert_eq!(lens, [1, 2, 3]);
     ///
-    /// assert_eq!(res, [10, 20, 30, 100]);
+    /// let res = Parallel::new().try_each(vec![Ok(1), Err("bad"), Ok(3)], |i| i * 2);
+    /// assert_eq!(res.err(), Some("bad"));
+    /// # Ok::<(), ()>(())
     /// ```
-    pub fn collect<C>(mut self) -> C
+    #[track_caller]
+    pub fn try_each<A, E, I, F>(mut self, iter: I, f: F) -> Result<Parallel<'a, T>, E>
     where
+        I: IntoIterator<Item = Result<A, E>>,
+        F: FnOnce(A) -> T + Clone + Send + 'a,
+        A: Send + 'a,
         T: Send + 'a,
-        C: FromIterator<T> + Extend<T>,
     {
-        // Get the last closure.
-        let f = match self.closures.pop() {
-            None => return iter::empty().collect(),
-            Some(f) => f,
-        };
-
-        // Spawn threads, run the last closure on the current thread.
-        let (mut results, r) = self.finish_in::<_, _, C>(f);
-        results.extend(Some(r));
-        results
+        let location = Location::caller();
+        let source = self.next_source();
+        let iter = iter.into_iter();
+        self.closures.reserve(iter.size_hint().0);
+        for t in iter {
+            match t {
+                Ok(t) => {
+                    let f = f.clone();
+                    self.closures
+                        .push(Box::new(move || at_location(location, || f(t))));
+                    self.sources.push(source);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self)
     }
 
-    /// Runs each closure on a separate thread and collects their results.
-    ///
-    /// Results are collected in the order in which closures were added. One of the closures always
-    /// runs on the main thread because there is no point in spawning an extra thread for it.
+    /// Adds a cloned closure for each mutable reference in an iterator.
     ///
-    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    /// Each clone of the closure gets exclusive access to its item, so items can be mutated in
+    /// place in parallel. This is the same as [`each()`][`Parallel::each()`] with an iterator like
+    /// [`slice::iter_mut()`], but spells out the intent.
     ///
     /// # Examples
     ///
     /// ```
     /// use easy_parallel::Parallel;
-    /// use std::thread;
-    /// use std::time::Duration;
     ///
-    /// let res = Parallel::new()
-    ///     .each(1..=3, |i| 10 * i)
-    ///     .add(|| 100)
+    /// let mut images = vec![vec![1u8, 2], vec![3, 4, 5]];
+    ///
+    /// let lens = Parallel::new()
+    ///     .each_mut(&mut images, |img| {
+    ///         img.iter_mut().for_each(|px| *px *= 2);
+    ///         img.len()
+    ///     })
     ///     .run();
     ///
-    /// assert_eq!(res, [10, 20, 30, 100]);
+    /// assert_eq!(lens, [2, 3]);
+    /// assert_eq!(images, [vec![2, 4], vec![6, 8, 10]]);
     /// ```
-    pub fn run(self) -> Vec<T>
+    #[track_caller]
+    pub fn each_mut<A, I, F>(self, iter: I, f: F) -> Parallel<'a, T>
     where
+        I: IntoIterator<Item = &'a mut A>,
+        F: FnOnce(&mut A) -> T + Clone + Send + 'a,
+        A: Send + 'a,
         T: Send + 'a,
     {
-        self.collect()
+        self.each(iter, f)
     }
 
-    /// Finishes with a closure to run on the main thread, starts threads, and collects results.
-    ///
-    /// Results are collected in the order in which closures were added.
+    /// Adds a cloned closure for each pair of items from two iterators.
     ///
-    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    /// Pairs are added in the order of their cartesian product: for each item of `a`, a closure is
+    /// added for every item of `b`. This is convenient for parameter sweeps.
     ///
     /// # Examples
     ///
     /// ```
     /// use easy_parallel::Parallel;
-    /// use std::thread;
-    /// use std::time::Duration;
     ///
-    /// let (res, ()) = Parallel::new()
-    ///     .each(1..=3, |i| 10 * i)
-    ///     .finish(|| println!("Waiting for results"));
+    /// let res = Parallel::new()
+    ///     .each_product(1..=2, [10, 20, 30], |x, y| x * y)
+    ///     .run();
     ///
-    /// assert_eq!(res, [10, 20, 30]);
+    /// assert_eq!(res, [10, 20, 30, 20, 40, 60]);
     /// ```
-    pub fn finish<F, R>(self, f: F) -> (Vec<T>, R)
+    #[track_caller]
+    pub fn each_product<A, B, IA, IB, F>(self, a: IA, b: IB, f: F) -> Parallel<'a, T>
     where
-        F: FnOnce() -> R,
+        IA: IntoIterator<Item = A>,
+        IB: IntoIterator<Item = B>,
+        IB::IntoIter: Clone,
+        F: FnOnce(A, B) -> T + Clone + Send + 'a,
+        A: Clone + Send + 'a,
+        B: Send + 'a,
         T: Send + 'a,
     {
-        self.finish_in::<_, _, Vec<T>>(f)
+        let b = b.into_iter();
+        let pairs = a
+            .into_iter()
+            .flat_map(move |x| b.clone().map(move |y| (x.clone(), y)));
+        self.each(pairs, move |(x, y)| f(x, y))
     }
 
-    /// Finishes with a closure to run on the main thread, starts threads, and collects results into an
-    /// arbitrary container.
-    ///
-    /// Results are collected in the order in which closures were added.
+    /// Adds a cloned closure for each keyed item that hasn't been completed yet, recording
+    /// completed keys in a sink.
     ///
-    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    /// Items whose key satisfies `is_done` are skipped. After a closure returns, its key is passed
+    /// to a clone of `sink`, which can append it to a journal. If a long batch is interrupted,
+    /// building it again with the journal as `is_done` skips the work that was already done.
+    /// Closures that panic don't record their keys.
     ///
     /// # Examples
     ///
     /// ```
     /// use easy_parallel::Parallel;
-    /// use std::thread;
-    /// use std::time::Duration;
+    /// use std::collections::HashSet;
+    /// use std::sync::Mutex;
     ///
-    /// let (res, ()) = Parallel::new()
-    ///     .each(1..=3, |i| 10 * i)
-    ///     .finish_in::<_, _, Vec<i32>>(|| println!("Waiting for results"));
+    /// let files = ["a.txt", "b.txt", "c.txt"];
     ///
-    /// assert_eq!(res, [10, 20, 30]);
+    /// // A journal left over from an interrupted run.
+    /// let journal = Mutex::new(HashSet::from(["a.txt"]));
+    /// let done = journal.lock().unwrap().clone();
+    ///
+    /// let res = Parallel::new()
+    ///     .each_resumable(
+    ///         files.iter().map(|&f| (f, f)),
+    ///         |f| done.contains(f),
+    ///         |f| {
+    ///             journal.lock().unwrap().insert(f);
+    ///         },
+    ///         |f| f.len(),
+    ///     )
+    ///     .run();
+    ///
+    /// assert_eq!(res, [5, 5]);
+    /// assert_eq!(journal.into_inner().unwrap().len(), 3);
     /// ```
-    pub fn finish_in<F, R, C>(self, f: F) -> (C, R)
+    #[track_caller]
+    pub fn each_resumable<K, A, I, D, S, F>(
+        self,
+        iter: I,
+        mut is_done: D,
+        sink: S,
+        f: F,
+    ) -> Parallel<'a, T>
     where
-        F: FnOnce() -> R,
+        I: IntoIterator<Item = (K, A)>,
+        D: FnMut(&K) -> bool,
+        S: FnOnce(K) + Clone + Send + 'a,
+        F: FnOnce(A) -> T + Clone + Send + 'a,
+        K: Send + 'a,
+        A: Send + 'a,
         T: Send + 'a,
-        C: FromIterator<T>,
     {
-        // Set up a new thread scope.
-        thread::scope(|scope| {
-            // Join handles for spawned threads.
-            let mut handles = Vec::new();
-
-            // Channels to collect results from spawned threads.
-            let mut receivers = Vec::new();
-
-            for f in self.closures.into_iter() {
-                // Wrap into a closure that sends the result back.
-                let (sender, receiver) = mpsc::channel();
-                let f = move || sender.send(f()).unwrap();
-
-                // Spawn it on the scope.
-                handles.push(scope.spawn(f));
-                receivers.push(receiver);
-            }
+        let pending = iter.into_iter().filter(|(k, _)| !is_done(k));
+        self.each(pending, move |(k, a)| {
+            let t = f(a);
+            sink(k);
+            t
+        })
+    }
 
-            let mut last_err = None;
+    /// Splits the list of closures into `n` smaller builders.
+    ///
+    /// Closures keep their order: running the returned builders one after another and
+    /// concatenating their results gives the same results as running the original builder. The
+    /// sizes of the returned builders differ by at most one, so some of them are empty if there
+    /// are fewer than `n` closures.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let mut parts = Parallel::new().each(0..5, |i| i * 10).split(2);
+    ///
+    /// let second = parts.pop().unwrap().run();
+    /// let first = parts.pop().unwrap().run();
+    ///
+    /// assert_eq!(first, [0, 10, 20]);
+    /// assert_eq!(second, [30, 40]);
+    /// ```
+    pub fn split(mut self, n: usize) -> Vec<Parallel<'a, T>> {
+        assert!(n > 0, "cannot split closures into zero parts");
 
-            // Run the main closure on the main thread.
-            let res = panic::catch_unwind(panic::AssertUnwindSafe(f));
+        let len = self.closures.len();
+        let mut closures = mem::take(&mut self.closures).into_iter();
+        let mut sources = mem::take(&mut self.sources).into_iter();
 
-            // Join threads and save the last panic if there was one.
-            for h in handles {
-                if let Err(err) = h.join() {
-                    last_err = Some(err);
+        (0..n)
+            .map(|i| {
+                // The first `len % n` parts get one extra closure.
+                let size = len / n + usize::from(i < len % n);
+                Parallel {
+                    closures: closures.by_ref().take(size).collect(),
+                    config: self.config.clone(),
+                    inspect: self.inspect.clone(),
+                    stepped: None,
+                    sources: sources.by_ref().take(size).collect(),
                 }
-            }
-
-            // If a thread has panicked, resume the last collected panic.
-            if let Some(err) = last_err {
-                panic::resume_unwind(err);
-            }
+            })
+            .collect()
+    }
 
-            // Collect the results from threads.
-            let results = receivers.into_iter().map(|r| r.recv().unwrap()).collect();
+    /// Limits how quickly closures may start to `per_second` closures per second.
+    ///
+    /// Closures still run on separate threads, but the closure at index `i` doesn't start until
+    /// `i / per_second` seconds after the batch has started running. This is useful when closures
+    /// call an external service with a strict request-rate quota.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` is not a positive finite number.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let start = Instant::now();
+    ///
+    /// Parallel::new()
+    ///     .each(0..5, |i| println!("request #{}", i))
+    ///     .rate_limit(50.0)
+    ///     .run();
+    ///
+    /// // The last closure starts 4/50 seconds after the first one.
+    /// assert!(start.elapsed() >= Duration::from_millis(80));
+    /// ```
+    pub fn rate_limit(mut self, per_second: f64) -> Parallel<'a, T> {
+        self.config = mem::take(&mut self.config).rate_limit(per_second);
+        self
+    }
 
-            // If the main closure panicked, resume its panic.
-            match res {
-                Ok(r) => (results, r),
-                Err(err) => panic::resume_unwind(err),
-            }
-        })
+    /// Limits the number of threads spawned for this batch to `n`.
+    ///
+    /// Instead of spawning a thread for each closure, up to `n` threads are spawned and take
+    /// closures from a queue, one after another, until all closures have run. The current thread
+    /// helps with the queue too, before running its own closure. This makes large batches like
+    /// `each(0..10_000, f)` cheap, and [`available_parallelism()`][thread::available_parallelism]
+    /// is a good choice for `n`.
+    ///
+    /// Since closures share threads, they must not wait for each other, like on a
+    /// [`Barrier`][std::sync::Barrier], or for the main closure of
+    /// [`finish()`][`Parallel::finish()`]. Batches started with [`start()`][`Parallel::start()`]
+    /// ignore the limit.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    ///
+    /// let n = thread::available_parallelism().map_or(1, |n| n.get());
+    ///
+    /// let squares = Parallel::new()
+    ///     .each(0..10_000u64, |i| i * i)
+    ///     .limit(n)
+    ///     .run();
+    ///
+    /// assert_eq!(squares.len(), 10_000);
+    /// assert_eq!(squares[100], 10_000);
+    /// ```
+    pub fn limit(mut self, n: usize) -> Parallel<'a, T> {
+        self.config = mem::take(&mut self.config).limit(n);
+        self
     }
-}
 
-impl<T> fmt::Debug for Parallel<'_, T> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Parallel")
-            .field("len", &self.closures.len())
-            .finish()
+    /// Takes queued closures from each `add()` or `each()` call in turn.
+    ///
+    /// Under a [`limit()`][`Parallel::limit()`], closures are taken from the queue in the order in
+    /// which they were added, so when several [`each()`][`Parallel::each()`] calls feed one
+    /// batch, the closures of the last call only start once all others have. With this option,
+    /// the queue takes one closure from each [`add()`][`Parallel::add()`] or `each()` call in
+    /// turn, so that no call is starved. The same goes for which closures get a thread when
+    /// limits set with [`ParallelConfig`] are reached.
+    ///
+    /// Results are still collected in the order in which closures were added. Closures waiting
+    /// for a [`rate_limit()`][`Parallel::rate_limit()`] still start in that order too.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..100, |i| ("bulk", i))
+    ///     .each(0..3, |i| ("interactive", i))
+    ///     .limit(2)
+    ///     .fair()
+    ///     .run();
+    ///
+    /// assert_eq!(res[100], ("interactive", 0));
+    /// ```
+    pub fn fair(mut self) -> Parallel<'a, T> {
+        self.config = mem::take(&mut self.config).fair();
+        self
     }
-}
 
-impl<T> Default for Parallel<'_, T> {
-    fn default() -> Self {
-        Self::new()
+    /// Holds back closures that haven't started yet while the token is paused.
+    ///
+    /// Right before a closure starts, its thread waits until the token is resumed. Under a
+    /// [`limit()`][`Parallel::limit()`], this stops queued closures from being taken, so an
+    /// interactive application can give the CPUs back to the user for a while. Closures that are
+    /// already running are not interrupted, but they can check
+    /// [`is_paused()`][`PauseToken::is_paused()`] or call [`wait()`][`PauseToken::wait()`] at
+    /// convenient points.
+    ///
+    /// The token must be resumed from outside the batch, since the main closure of
+    /// [`finish()`][`Parallel::finish()`] may only run once the queue is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, PauseToken};
+    /// use std::thread;
+    /// use std::time::{Duration, Instant};
+    ///
+    /// let token = PauseToken::new();
+    /// token.pause();
+    ///
+    /// let resume = thread::spawn({
+    ///     let token = token.clone();
+    ///     move || {
+    ///         thread::sleep(Duration::from_millis(50));
+    ///         token.resume();
+    ///     }
+    /// });
+    ///
+    /// let start = Instant::now();
+    /// let v = Parallel::new()
+    ///     .each(0..4, |i| i)
+    ///     .limit(2)
+    ///     .pausable(&token)
+    ///     .run();
+    ///
+    /// assert_eq!(v, [0, 1, 2, 3]);
+    /// assert!(start.elapsed() >= Duration::from_millis(50));
+    /// resume.join().unwrap();
+    /// ```
+    pub fn pausable(mut self, token: &PauseToken) -> Parallel<'a, T> {
+        self.config = mem::take(&mut self.config).pausable(token);
+        self
     }
+
+    /// Sets a thread-local value for each closure while it runs.
+    ///
+    /// Before a closure runs, `init` is called on the thread running it and its result is stored
+    /// in the thread-local `key`, so that code deep in the call stack, like a logger, can read
+    /// data such as a request ID without it being passed to every closure. Closures may also
+    /// change the value. Once the closure has run, the previous value of the thread-local is
+    /// restored, even if the closure panicked, so closures sharing a thread under
+    /// [`limit()`][`Parallel::limit()`] each start with a fresh value.
+    ///
+    /// When `init` is called, [`TaskInfo::current()`] already has the index of the task and the
+    /// total number of tasks, but not its [`location()`][`TaskInfo::location()`] yet, which is
+    /// only recorded once the closure itself starts.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::cell::RefCell;
+    ///
+    /// thread_local! {
+    ///     static REQUEST_ID: RefCell<u64> = const { RefCell::new(0) };
+    /// }
+    ///
+    /// fn log(msg: &str) -> String {
+    ///     REQUEST_ID.with(|id| format!("[request {}] {}", id.borrow(), msg))
+    /// }
+    ///
+    /// let lines = Parallel::new()
+    ///     .each(0..3, |i| log(&format!("step {}", i)))
+    ///     .task_local(&REQUEST_ID, || 7)
+    ///     .limit(1)
+    ///     .run();
+    ///
+    /// assert_eq!(lines, ["[request 7] step 0", "[request 7] step 1", "[request 7] step 2"]);
+    /// assert_eq!(log("done"), "[request 0] done");
+    /// ```
+    pub fn task_local<L, F>(
+        mut self,
+        key: &'static LocalKey<RefCell<L>>,
+        init: F,
+    ) -> Parallel<'a, T>
+    where
+        L: 'static,
+        F: Fn() -> L + Send + Sync + 'static,
+    {
+        self.config = mem::take(&mut self.config).task_local(key, init);
+        self
+    }
+
+    /// Applies settings shared by many builders.
+    ///
+    /// This replaces any settings made earlier on this builder, such as
+    /// [`rate_limit()`][`Parallel::rate_limit()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, ParallelConfig};
+    ///
+    /// let config = ParallelConfig::new().name("worker").stack_size(1 << 20);
+    ///
+    /// let a = Parallel::new().each(0..3, |i| i).with_config(&config).run();
+    /// let b = Parallel::new().each(0..3, |i| -i).with_config(&config).run();
+    ///
+    /// assert_eq!(a, [0, 1, 2]);
+    /// assert_eq!(b, [0, -1, -2]);
+    /// ```
+    pub fn with_config(mut self, config: &ParallelConfig) -> Parallel<'a, T> {
+        self.config = config.clone();
+        self
+    }
+
+    /// Sets a hook that is called with the index and the result of each closure as results are
+    /// collected.
+    ///
+    /// The hook runs on the main thread, before each result is put into the output. This is
+    /// useful for cheap logging or validation. It is called by every method that produces
+    /// results: those that collect them, such as [`run()`][`Parallel::run()`] and
+    /// [`finish()`][`Parallel::finish()`], call it as results are collected, the iterators passed
+    /// to [`finish_ordered()`][`Parallel::finish_ordered()`] and
+    /// [`finish_streaming()`][`Parallel::finish_streaming()`] call it as results are yielded,
+    /// [`send_results_to()`][`Parallel::send_results_to()`] calls it before sending each result,
+    /// and [`Running::join()`] calls it for batches started with
+    /// [`start()`][`Parallel::start()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(1..=3, |i| i * 10)
+    ///     .inspect(|index, t| println!("closure #{} returned {}", index, t))
+    ///     .run();
+    ///
+    /// assert_eq!(res, [10, 20, 30]);
+    /// ```
+    pub fn inspect<F>(mut self, f: F) -> Parallel<'a, T>
+    where
+        F: Fn(usize, &T) + Send + Sync + 'a,
+    {
+        self.inspect = Some(Arc::new(f));
+        self
+    }
+
+    /// Returns the number of closures in the list.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let p = Parallel::new().each(0..3, |i| i);
+    /// assert_eq!(p.len(), 3);
+    /// # p.run();
+    /// ```
+    pub fn len(&self) -> usize {
+        self.closures.len()
+    }
+
+    /// Returns `true` if the list of closures is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let p = Parallel::<()>::new();
+    /// assert!(p.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.closures.is_empty()
+    }
+
+    /// Returns the number of threads the closures will run on, including the current thread.
+    ///
+    /// This is the number of closures, unless fewer threads can be spawned. That is the case on
+    /// targets that don't support threads, in which case all closures run on the current thread,
+    /// and when the [`limit()`][`Parallel::limit()`], the
+    /// [`max_depth()`][ParallelConfig::max_depth] or the
+    /// [`max_threads()`][ParallelConfig::max_threads] setting applies. Since other batches may
+    /// start or finish threads in the meantime, the number is only an estimate.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let p = Parallel::new().each(0..4, |i| i);
+    /// assert!(p.effective_threads() <= 4);
+    /// # p.run();
+    /// ```
+    pub fn effective_threads(&self) -> usize {
+        let spawnable = self.config.spawnable_threads();
+        self.closures.len().min(spawnable.saturating_add(1))
+    }
+
+    /// Returns `true` if the closures will run on more than one thread.
+    ///
+    /// This is useful for choosing between a parallel and a sequential algorithm, or for picking
+    /// chunk sizes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let p = Parallel::new().add(|| 1).add(|| 2);
+    ///
+    /// if !p.will_parallelize() {
+    ///     println!("Running sequentially");
+    /// }
+    /// # p.run();
+    /// ```
+    pub fn will_parallelize(&self) -> bool {
+        self.effective_threads() > 1
+    }
+
+    /// Runs each closure on a separate thread and collects their results.
+    ///
+    /// Results are collected in the order in which closures were added. One of the closures always
+    /// runs on the main thread because there is no point in spawning an extra thread for it.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .add(|| 100)
+    ///     .collect::<Vec<_>>();
+    ///
+    /// assert_eq!(res, [10, 20, 30, 100]);
+    /// ```
+    ///
+    /// Results are buffered per closure until all threads are joined and then inserted into the
+    /// container one by one, so the container only ever holds what it keeps. Collecting into a
+    /// set deduplicates results during insertion:
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::collections::HashSet;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..10, |i| i % 3)
+    ///     .collect::<HashSet<_>>();
+    ///
+    /// assert_eq!(res, HashSet::from([0, 1, 2]));
+    /// ```
+    pub fn collect<C>(self) -> C
+    where
+        T: Send + 'a,
+        C: FromIterator<T> + Extend<T>,
+    {
+        self.indexed().collect_indexed()
+    }
+
+    /// Runs each closure on a separate thread and collects their results in the order in which
+    /// closures complete.
+    ///
+    /// All results go through a single channel and are inserted into the container on the main
+    /// thread while the other closures are still running, so collection finishes as soon as the
+    /// last closure does. Under a [`limit()`][`Parallel::limit()`], the main thread collects
+    /// results before it helps with the queue. This suits containers where order doesn't matter,
+    /// like sets, sums, or maps keyed by data.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::collections::{HashMap, HashSet};
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..10, |i| i % 3)
+    ///     .collect_unordered::<HashSet<_>>();
+    ///
+    /// assert_eq!(res, HashSet::from([0, 1, 2]));
+    ///
+    /// let lengths: HashMap<_, _> = Parallel::new()
+    ///     .each(["kiwi", "apple"], |w| (w, w.len()))
+    ///     .collect_unordered();
+    ///
+    /// assert_eq!(lengths["apple"], 5);
+    /// ```
+    pub fn collect_unordered<C>(mut self) -> C
+    where
+        T: Send + 'a,
+        C: FromIterator<T>,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let inspect = self.inspect.take();
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.closures.reserve(self.closures.len());
+        for (index, f) in mem::take(&mut self.closures).into_iter().enumerate() {
+            let sender = sender.clone();
+            parallel = parallel.add(move || sender.send((index, f())).unwrap_or(()));
+        }
+        parallel.sources = mem::take(&mut self.sources);
+
+        // Drop the original sender so that the channel closes once all closures have completed.
+        drop(sender);
+        let ((), collected) = parallel.indexed().spawn_and_finish(true, || {
+            receiver
+                .into_iter()
+                .map(|(index, t)| {
+                    if let Some(inspect) = &inspect {
+                        inspect(index, &t);
+                    }
+                    t
+                })
+                .collect()
+        });
+        collected
+    }
+
+    /// Runs each closure on a separate thread and collects their results.
+    ///
+    /// Results are collected in the order in which closures were added. One of the closures always
+    /// runs on the main thread because there is no point in spawning an extra thread for it.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .add(|| 100)
+    ///     .run();
+    ///
+    /// assert_eq!(res, [10, 20, 30, 100]);
+    /// ```
+    pub fn run(self) -> Vec<T>
+    where
+        T: Send + 'a,
+    {
+        self.collect()
+    }
+
+    /// Runs each closure on a separate thread and collects their results, panicking if there are
+    /// no closures.
+    ///
+    /// This is the same as [`run()`][`Parallel::run()`], except that an empty builder is treated as
+    /// a bug instead of silently producing no results. This catches cases like an unexpectedly
+    /// empty iterator passed to [`each()`][`Parallel::each()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if no closures were added. If a closure panics, panicking will resume in the main
+    /// thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new().each(1..=3, |i| 10 * i).run_nonempty();
+    /// assert_eq!(res, [10, 20, 30]);
+    /// ```
+    ///
+    /// ```should_panic
+    /// use easy_parallel::Parallel;
+    ///
+    /// let files: Vec<&str> = Vec::new();
+    /// Parallel::new().each(files, |f| f.len()).run_nonempty();
+    /// ```
+    #[track_caller]
+    pub fn run_nonempty(self) -> Vec<T>
+    where
+        T: Send + 'a,
+    {
+        assert!(
+            !self.closures.is_empty(),
+            "`Parallel::run_nonempty()` called without any closures"
+        );
+        self.run()
+    }
+
+    /// Runs each closure on a separate thread, collects their results, and records a trace of
+    /// when each closure ran.
+    ///
+    /// The returned [`Trace`] can be written as a Chrome trace event file, which can be opened in
+    /// `chrome://tracing` or [Perfetto](https://ui.perfetto.dev) to spot stragglers.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let (res, trace) = Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .run_traced();
+    ///
+    /// let mut json = Vec::new();
+    /// trace.write_json(&mut json).unwrap();
+    ///
+    /// assert_eq!(res, [10, 20, 30]);
+    /// ```
+    #[cfg(feature = "trace")]
+    pub fn run_traced(self) -> (Vec<T>, Trace)
+    where
+        T: Send + 'a,
+    {
+        let (results, spans) = self.timed().run().into_iter().unzip();
+        (results, Trace { spans })
+    }
+
+    /// Runs each closure on a separate thread, collects their results, and summarizes how long
+    /// the closures ran.
+    ///
+    /// The returned [`Summary`] contains percentiles of the durations of closures and of how long
+    /// they waited to start, which is usually what is worth logging for monitoring.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let (res, summary) = Parallel::new()
+    ///     .each(1..=3, |i| {
+    ///         thread::sleep(Duration::from_millis(10 * i));
+    ///         i
+    ///     })
+    ///     .run_with_summary();
+    ///
+    /// assert_eq!(res, [1, 2, 3]);
+    /// assert!(summary.max() >= Duration::from_millis(30));
+    /// assert!(summary.p50() <= summary.max());
+    /// ```
+    pub fn run_with_summary(self) -> (Vec<T>, Summary)
+    where
+        T: Send + 'a,
+    {
+        let (results, spans): (Vec<T>, Vec<Span>) = self.timed().run().into_iter().unzip();
+        (results, Summary::new(&spans))
+    }
+
+    /// Runs each closure on a separate thread and checks that all results are equal.
+    ///
+    /// Returns the agreed result if all closures returned equal values. Otherwise, returns a
+    /// [`Disagreement`] that groups the indices of closures by the value they returned. This is
+    /// useful for differential testing, where the same input is fed to several implementations.
+    ///
+    /// If no closures were added, a [`Disagreement`] without any groups is returned.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let v = [3, 1, 2];
+    ///
+    /// let max = Parallel::new()
+    ///     .add(|| v.iter().copied().max().unwrap())
+    ///     .add(|| v.iter().copied().fold(0, i32::max))
+    ///     .run_consensus();
+    /// assert_eq!(max, Ok(3));
+    ///
+    /// let err = Parallel::new()
+    ///     .add(|| 1)
+    ///     .add(|| 2)
+    ///     .add(|| 1)
+    ///     .run_consensus()
+    ///     .unwrap_err();
+    /// assert_eq!(err.groups(), [(1, vec![0, 2]), (2, vec![1])]);
+    /// ```
+    pub fn run_consensus(self) -> Result<T, Disagreement<T>>
+    where
+        T: Send + PartialEq + 'a,
+    {
+        let mut groups: Vec<(T, Vec<usize>)> = Vec::new();
+
+        for (i, t) in self.run().into_iter().enumerate() {
+            match groups.iter_mut().find(|(g, _)| *g == t) {
+                Some((_, indices)) => indices.push(i),
+                None => groups.push((t, vec![i])),
+            }
+        }
+
+        if groups.len() == 1 {
+            Ok(groups.pop().unwrap().0)
+        } else {
+            Err(Disagreement { groups })
+        }
+    }
+
+    /// Runs the closures of two builders in parallel and zips their results pairwise.
+    ///
+    /// The closures of `other` run as a batch nested inside the main closure of
+    /// [`finish()`][`Parallel::finish()`] on this builder, one nesting level deeper, so
+    /// [`max_depth()`][ParallelConfig::max_depth] counts that level. Both batches run at the same
+    /// time, except that closures of this builder that don't get a thread of their own, like
+    /// under a [`limit()`][`Parallel::limit()`] or limits set with [`ParallelConfig`], run
+    /// before `other` starts. The `i`-th pair contains the results of the `i`-th closure of
+    /// each builder. If one builder has more closures than the other, the results of its extra
+    /// closures are discarded, just like with [`Iterator::zip()`].
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let words = ["apple", "kiwi", "banana"];
+    ///
+    /// let res = Parallel::new()
+    ///     .each(words, |w| w.len())
+    ///     .run_zip(Parallel::new().each(words, |w| w.contains('a')));
+    ///
+    /// assert_eq!(res, [(5, true), (4, false), (6, true)]);
+    /// ```
+    pub fn run_zip<U>(self, other: Parallel<'a, U>) -> Vec<(T, U)>
+    where
+        T: Send + 'a,
+        U: Send + 'a,
+    {
+        let (a, b) = self.finish(|| other.run());
+        a.into_iter().zip(b).collect()
+    }
+
+    /// Runs as many of the remaining closures as fit in a time budget and collects their results.
+    ///
+    /// Closures run in rounds of up to one per [available][thread::available_parallelism] thread,
+    /// in the order in which they were added. New rounds start until the budget is exhausted, and
+    /// the closures that didn't get to run stay in the builder for the next call. This way, a large
+    /// batch can be spread across the frames of a game or UI loop without a background pool.
+    ///
+    /// At least one round runs on every call, and a round that has started always runs to
+    /// completion, so a step may take longer than the budget. A budget of [`Duration::MAX`] runs
+    /// all remaining closures.
+    ///
+    /// Across steps, closures keep the indices they would have in a single batch, as seen by
+    /// [`current_task_index()`], [`TaskInfo`], and the [`inspect()`][`Parallel::inspect()`] hook,
+    /// and the [`rate_limit()`][`Parallel::rate_limit()`] spaces out their starts from the first
+    /// step on.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    /// The closures that didn't get to run are then discarded.
+    ///
+    /// Dropping the builder discards the closures that haven't run yet, so keep running it until
+    /// [`is_empty()`][`Parallel::is_empty()`] returns `true`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::time::Duration;
+    ///
+    /// let mut p = Parallel::new().each(0..100, |i| i * 2);
+    /// let mut res = Vec::new();
+    ///
+    /// while !p.is_empty() {
+    ///     // Render a frame here...
+    ///     res.extend(p.run_step(Duration::from_millis(5)));
+    /// }
+    ///
+    /// assert_eq!(res, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+    /// ```
+    pub fn run_step(&mut self, budget: Duration) -> Vec<T>
+    where
+        T: Send + 'a,
+    {
+        // A budget too large to represent a point in time means there is no deadline.
+        let deadline = Instant::now().checked_add(budget);
+        let mut results = Vec::new();
+
+        // Number closures and space out their starts across all steps, as if they ran in one batch.
+        let (origin, mut done) = *self.stepped.get_or_insert_with(|| (Instant::now(), 0));
+        let total = done + self.closures.len();
+
+        while !self.closures.is_empty() {
+            let n = available_threads().min(self.closures.len());
+            let round = Parallel {
+                closures: self.closures.drain(..n).collect(),
+                config: self.config.clone(),
+                inspect: self.inspect.clone(),
+                stepped: None,
+                sources: self.sources.drain(..n).collect(),
+            }
+            .indexed_at(done, total, origin);
+            done += n;
+            self.stepped = Some((origin, done));
+
+            // Discard the remaining closures if the round panics.
+            match catch_unwind(|| round.collect_indexed::<Vec<T>>()) {
+                Ok(res) => results.extend(res),
+                Err(err) => {
+                    self.closures.clear();
+                    self.sources.clear();
+                    panic::resume_unwind(err);
+                }
+            }
+
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                break;
+            }
+        }
+        results
+    }
+
+    /// Finishes with a closure to run on the main thread, starts threads, and collects results.
+    ///
+    /// Results are collected in the order in which closures were added.
+    ///
+    /// The main closure runs while the other closures are running on their threads. Closures that
+    /// don't get a thread of their own run on the current thread before the main closure, though.
+    /// This happens when limits set with [`ParallelConfig`] are reached, when a thread cannot be
+    /// spawned, and on targets without threads. So other closures must not wait for the main
+    /// closure, like by receiving data it sends, or they will wait forever.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let (res, ()) = Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .finish(|| println!("Waiting for results"));
+    ///
+    /// assert_eq!(res, [10, 20, 30]);
+    /// ```
+    pub fn finish<F, R>(self, f: F) -> (Vec<T>, R)
+    where
+        F: FnOnce() -> R,
+        T: Send + 'a,
+    {
+        self.finish_in::<_, _, Vec<T>>(f)
+    }
+
+    /// Finishes with a closure to run on the main thread, starts threads, and collects results,
+    /// keeping the results even if the main closure panics.
+    ///
+    /// This is the same as [`finish()`][`Parallel::finish()`], except that a panic in the main
+    /// closure is returned as an error alongside the results of the other closures, so that
+    /// expensive results are not lost.
+    ///
+    /// If another closure panics, panicking will resume in the main thread after all threads are
+    /// joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let (res, main) = Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .try_finish(|| -> () { panic!("main closure failed") });
+    ///
+    /// assert_eq!(res, [10, 20, 30]);
+    /// assert!(main.is_err());
+    /// ```
+    pub fn try_finish<F, R>(self, f: F) -> (Vec<T>, thread::Result<R>)
+    where
+        F: FnOnce() -> R,
+        T: Send + 'a,
+    {
+        self.finish(|| catch_unwind(f))
+    }
+
+    /// Runs each closure on a separate thread and collects their results, capturing panics.
+    ///
+    /// Results are collected in the order in which closures were added. A closure that panics
+    /// results in an error holding its panic payload, and the results of all other closures are
+    /// kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..3, |i| if i == 1 { panic!("bad input") } else { i * 10 })
+    ///     .run_catch();
+    ///
+    /// assert_eq!(res[0].as_ref().ok(), Some(&0));
+    /// assert!(res[1].is_err());
+    /// assert_eq!(res[2].as_ref().ok(), Some(&20));
+    /// ```
+    pub fn run_catch(self) -> Vec<thread::Result<T>>
+    where
+        T: Send + 'a,
+    {
+        self.catching().run()
+    }
+
+    /// Runs each closure on a separate thread and collects the [`Outcome`] of each closure.
+    ///
+    /// Outcomes are collected in the order in which closures were added. Like with
+    /// [`run_catch()`][`Parallel::run_catch()`], a closure that panics results in
+    /// [`Outcome::Panicked`] and the other results are kept. Closures that haven't started by the
+    /// time `token` is cancelled are skipped and result in [`Outcome::Cancelled`], and those that
+    /// haven't started once `budget` has passed are skipped and result in [`Outcome::TimedOut`].
+    /// Closures that have started always run to completion. A budget of [`Duration::MAX`] means
+    /// there is no deadline.
+    ///
+    /// Closures only wait to start under a [`limit()`][`Parallel::limit()`] or a
+    /// [`rate_limit()`][`Parallel::rate_limit()`]. Running closures can cancel `token` to skip
+    /// the rest of the batch.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{CancelToken, Outcome, Parallel};
+    /// use std::time::Duration;
+    ///
+    /// let token = CancelToken::new();
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..3, |i| if i == 1 { panic!("bad input") } else { i * 10 })
+    ///     .run_outcomes(&token, Duration::MAX);
+    ///
+    /// assert!(matches!(res[0], Outcome::Completed(0)));
+    /// assert!(matches!(res[1], Outcome::Panicked(_)));
+    /// assert!(matches!(res[2], Outcome::Completed(20)));
+    ///
+    /// token.cancel();
+    /// let res = Parallel::new()
+    ///     .each(0..3, |i| i)
+    ///     .run_outcomes(&token, Duration::MAX);
+    ///
+    /// assert!(res.iter().all(|o| matches!(o, Outcome::Cancelled)));
+    /// ```
+    pub fn run_outcomes(self, token: &CancelToken, budget: Duration) -> Vec<Outcome<T>>
+    where
+        T: Send + 'a,
+    {
+        // A budget too large to represent a point in time means there is no deadline.
+        let deadline = Instant::now().checked_add(budget);
+        self.with_outcomes(token, deadline).run()
+    }
+
+    /// Finishes with a closure to run on the main thread, starts threads, and collects results,
+    /// capturing panics.
+    ///
+    /// This is the same as [`finish()`][`Parallel::finish()`], except that panics in any of the
+    /// closures, including the main closure, are returned as errors holding their panic payloads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let (res, main) = Parallel::new()
+    ///     .add(|| 1)
+    ///     .add(|| panic!("worker failed"))
+    ///     .finish_catch(|| "done");
+    ///
+    /// assert_eq!(res[0].as_ref().ok(), Some(&1));
+    /// assert!(res[1].is_err());
+    /// assert_eq!(main.ok(), Some("done"));
+    /// ```
+    pub fn finish_catch<F, R>(self, f: F) -> (Vec<thread::Result<T>>, thread::Result<R>)
+    where
+        F: FnOnce() -> R,
+        T: Send + 'a,
+    {
+        self.catching().try_finish(f)
+    }
+
+    /// Runs each closure on a separate thread and collects their results, skipping the closures
+    /// that haven't started once `token` is cancelled.
+    ///
+    /// Closures can hold clones of `token` and cancel it when they find that the rest of the
+    /// batch is pointless, like on a fatal configuration error. The closures that haven't started
+    /// yet are then skipped and their results are `None`, while closures that are already running
+    /// finish normally. Results are collected in the order in which closures were added.
+    ///
+    /// Closures only wait to start under a [`limit()`][`Parallel::limit()`] or a
+    /// [`rate_limit()`][`Parallel::rate_limit()`]. Otherwise, all of them start right away and
+    /// none are skipped unless `token` was cancelled before the batch ran.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{CancelToken, Parallel};
+    ///
+    /// let token = CancelToken::new();
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..100, |i| {
+    ///         if i == 0 {
+    ///             // The configuration is broken, so the other closures would fail too.
+    ///             token.cancel();
+    ///         }
+    ///         i
+    ///     })
+    ///     .limit(2)
+    ///     .run_cancellable(&token);
+    ///
+    /// assert_eq!(res.len(), 100);
+    /// assert_eq!(res[0], Some(0));
+    /// ```
+    pub fn run_cancellable(self, token: &CancelToken) -> Vec<Option<T>>
+    where
+        T: Send + 'a,
+    {
+        self.skippable(token).run()
+    }
+
+    /// Finishes with a closure to run on the main thread that can cancel the batch, starts
+    /// threads, and collects results.
+    ///
+    /// Once `token` is cancelled, by the main closure or from anywhere else, closures that haven't
+    /// started yet are skipped and their results are `None`. Closures that are already running
+    /// finish normally, but they can hold clones of `token` and poll
+    /// [`CancelToken::is_cancelled()`] to stop early. This is useful when the main closure runs
+    /// an event loop that may see a shutdown request.
+    ///
+    /// Closures that wait for their turn under a [`limit()`][`Parallel::limit()`] or a
+    /// [`rate_limit()`][`Parallel::rate_limit()`] haven't started yet. Unlike with
+    /// [`finish()`][`Parallel::finish()`], the main closure runs while the queue under a limit is
+    /// worked off, as long as at least one thread could be spawned for it. Closures that don't get
+    /// a thread otherwise still run before the main closure.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{CancelToken, Parallel};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let token = CancelToken::new();
+    ///
+    /// let (res, ()) = Parallel::new()
+    ///     .each(0..100, |i| {
+    ///         thread::sleep(Duration::from_millis(1));
+    ///         i
+    ///     })
+    ///     .limit(1)
+    ///     .finish_cancellable(&token, || {
+    ///         // A shutdown request came in.
+    ///         token.cancel();
+    ///     });
+    ///
+    /// assert_eq!(res.len(), 100);
+    /// assert!(res.iter().any(|r| r.is_none()));
+    /// ```
+    pub fn finish_cancellable<F, R>(self, token: &CancelToken, f: F) -> (Vec<Option<T>>, R)
+    where
+        F: FnOnce() -> R,
+        T: Send + 'a,
+    {
+        self.skippable(token).indexed().spawn_and_finish(true, f)
+    }
+
+    /// Finishes with a closure to run on the main thread, starts threads, and collects results into an
+    /// arbitrary container.
+    ///
+    /// Results are collected in the order in which closures were added.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let (res, ()) = Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .finish_in::<_, _, Vec<i32>>(|| println!("Waiting for results"));
+    ///
+    /// assert_eq!(res, [10, 20, 30]);
+    /// ```
+    pub fn finish_in<F, R, C>(self, f: F) -> (C, R)
+    where
+        F: FnOnce() -> R,
+        T: Send + 'a,
+        C: FromIterator<T>,
+    {
+        self.indexed().spawn_and_finish(false, f)
+    }
+
+    /// Wraps each closure so that its panic is returned as an error.
+    ///
+    /// The inspection hook only sees the results of closures that didn't panic.
+    fn catching(mut self) -> Parallel<'a, thread::Result<T>>
+    where
+        T: Send + 'a,
+    {
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        if let Some(inspect) = self.inspect.take() {
+            parallel = parallel.inspect(move |index, res: &thread::Result<T>| {
+                if let Ok(t) = res {
+                    inspect(index, t);
+                }
+            });
+        }
+        parallel.closures.reserve(self.closures.len());
+        for f in mem::take(&mut self.closures) {
+            parallel = parallel.add(move || catch_unwind(f));
+        }
+        parallel.sources = mem::take(&mut self.sources);
+        parallel
+    }
+
+    /// Wraps each closure so that it returns its [`Outcome`], skipping it if `token` has been
+    /// cancelled or `deadline` has passed by the time it starts.
+    ///
+    /// The inspection hook only sees the results of closures that completed.
+    fn with_outcomes(
+        mut self,
+        token: &CancelToken,
+        deadline: Option<Instant>,
+    ) -> Parallel<'a, Outcome<T>>
+    where
+        T: Send + 'a,
+    {
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        if let Some(inspect) = self.inspect.take() {
+            parallel = parallel.inspect(move |index, outcome: &Outcome<T>| {
+                if let Outcome::Completed(t) = outcome {
+                    inspect(index, t);
+                }
+            });
+        }
+        parallel.closures.reserve(self.closures.len());
+        for f in mem::take(&mut self.closures) {
+            let token = token.clone();
+            parallel = parallel.add(move || {
+                if token.is_cancelled() {
+                    Outcome::Cancelled
+                } else if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                    Outcome::TimedOut
+                } else {
+                    match catch_unwind(f) {
+                        Ok(t) => Outcome::Completed(t),
+                        Err(err) => Outcome::Panicked(err),
+                    }
+                }
+            });
+        }
+        parallel.sources = mem::take(&mut self.sources);
+        parallel
+    }
+
+    /// Wraps each closure so that it is skipped and returns `None` if `token` has been cancelled
+    /// by the time it starts.
+    ///
+    /// The inspection hook only sees the results of closures that ran.
+    fn skippable(mut self, token: &CancelToken) -> Parallel<'a, Option<T>>
+    where
+        T: Send + 'a,
+    {
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        if let Some(inspect) = self.inspect.take() {
+            parallel = parallel.inspect(move |index, res: &Option<T>| {
+                if let Some(t) = res {
+                    inspect(index, t);
+                }
+            });
+        }
+        parallel.closures.reserve(self.closures.len());
+        for f in mem::take(&mut self.closures) {
+            let token = token.clone();
+            parallel = parallel.add(move || {
+                if token.is_cancelled() {
+                    None
+                } else {
+                    Some(f())
+                }
+            });
+        }
+        parallel.sources = mem::take(&mut self.sources);
+        parallel
+    }
+
+    /// Wraps each closure so that it also returns the span of time during which it ran.
+    fn timed(mut self) -> Parallel<'a, (T, Span)>
+    where
+        T: Send + 'a,
+    {
+        let origin = Instant::now();
+        let interval = self.config.interval;
+
+        // Threads that ran closures, in the order in which they started their first closure.
+        let workers = Arc::new(Mutex::new(vec![thread::current().id()]));
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        if let Some(inspect) = self.inspect.take() {
+            parallel = parallel.inspect(move |index, (t, _)| inspect(index, t));
+        }
+        parallel.closures.reserve(self.closures.len());
+        for f in mem::take(&mut self.closures) {
+            let workers = workers.clone();
+            parallel = parallel.add(move || {
+                let worker = {
+                    let id = thread::current().id();
+                    let mut workers = workers.lock().unwrap();
+                    workers.iter().position(|&w| w == id).unwrap_or_else(|| {
+                        workers.push(id);
+                        workers.len() - 1
+                    })
+                };
+                let start = origin.elapsed();
+                let eligible = match (interval, TaskInfo::current()) {
+                    (Some(interval), Some(info)) => start_slot(interval, info.index),
+                    _ => Duration::ZERO,
+                };
+                let t = f();
+                let span = Span {
+                    start,
+                    end: origin.elapsed(),
+                    queued: start.saturating_sub(eligible),
+                    worker,
+                    location: TaskInfo::current().and_then(|info| info.location),
+                };
+                (t, span)
+            });
+        }
+        parallel.sources = mem::take(&mut self.sources);
+        parallel
+    }
+
+    /// Returns the number of the next `add()` or `each()` call.
+    fn next_source(&self) -> usize {
+        self.sources.last().map_or(0, |&source| source + 1)
+    }
+
+    /// Orders jobs made from the closures in the order in which they should be dispatched.
+    ///
+    /// With [`fair()`][`Parallel::fair()`], jobs are taken from each `add()` or `each()` call in
+    /// turn. Otherwise, they keep the order in which closures were added.
+    fn dispatch_order<J>(&self, jobs: Vec<J>) -> Vec<J> {
+        if !self.config.fair {
+            return jobs;
+        }
+
+        // Group jobs by the call that added them, then take one job from each group in turn.
+        let mut groups = Vec::<vec::IntoIter<J>>::new();
+        let mut group = Vec::new();
+        let mut current = None;
+        for (job, &source) in jobs.into_iter().zip(&self.sources) {
+            if current != Some(source) && !group.is_empty() {
+                groups.push(mem::take(&mut group).into_iter());
+            }
+            current = Some(source);
+            group.push(job);
+        }
+        groups.push(group.into_iter());
+
+        let mut ordered = Vec::with_capacity(self.sources.len());
+        while !groups.is_empty() {
+            groups.retain_mut(|group| match group.next() {
+                Some(job) => {
+                    ordered.push(job);
+                    true
+                }
+                None => false,
+            });
+        }
+        ordered
+    }
+
+    /// Wraps each closure so that [`current_task_index()`] returns its index while it runs.
+    ///
+    /// If a rate limit is set, each closure also waits for its turn to start.
+    fn indexed(self) -> Parallel<'a, T>
+    where
+        T: Send + 'a,
+    {
+        let total = self.closures.len();
+        self.indexed_at(0, total, Instant::now())
+    }
+
+    /// Wraps each closure like [`Parallel::indexed()`], numbering closures from `offset` in a
+    /// batch of `total` closures that started running at `origin`.
+    ///
+    /// The inspection hook sees the same indices.
+    fn indexed_at(mut self, offset: usize, total: usize, origin: Instant) -> Parallel<'a, T>
+    where
+        T: Send + 'a,
+    {
+        let closures = mem::take(&mut self.closures);
+        let interval = self.config.interval.take();
+        let pause = self.config.pause.take();
+        let locals: Arc<[TaskLocalHook]> = self.config.task_locals.clone().into();
+        let inspect = match self.inspect.take() {
+            Some(inspect) if offset > 0 => {
+                Some(Arc::new(move |index, t: &T| inspect(offset + index, t)) as Inspect<'a, T>)
+            }
+            inspect => inspect,
+        };
+
+        Parallel {
+            closures: closures
+                .into_iter()
+                .zip(offset..)
+                .map(|(f, index)| -> Box<dyn FnOnce() -> T + Send + 'a> {
+                    let locals = locals.clone();
+                    let pause = pause.clone();
+                    Box::new(move || {
+                        if let Some(interval) = interval {
+                            let delay = start_slot(interval, index);
+                            thread::sleep(delay.saturating_sub(origin.elapsed()));
+                        }
+                        if let Some(pause) = &pause {
+                            pause.wait();
+                        }
+                        in_task(index, total, || with_task_locals(&locals, f))
+                    })
+                })
+                .collect(),
+            config: mem::take(&mut self.config),
+            inspect,
+            stepped: None,
+            sources: mem::take(&mut self.sources),
+        }
+    }
+
+    /// Spawns threads for closures wrapped by [`Parallel::indexed()`], runs the last closure on
+    /// the current thread, and collects results.
+    fn collect_indexed<C>(mut self) -> C
+    where
+        T: Send + 'a,
+        C: FromIterator<T> + Extend<T>,
+    {
+        // Get the last closure.
+        let f = match self.closures.pop() {
+            None => return iter::empty().collect(),
+            Some(f) => f,
+        };
+        self.sources.pop();
+        let last = self.closures.len();
+        let inspect = self.inspect.clone();
+
+        // Spawn threads, run the last closure on the current thread.
+        let (mut results, r) = self.spawn_and_finish::<_, _, C>(false, f);
+        if let Some(inspect) = inspect {
+            inspect(last, &r);
+        }
+        results.extend(Some(r));
+        results
+    }
+
+    /// Spawns a thread for each closure, runs `f` on the current thread, and collects results.
+    ///
+    /// See [`spawn_jobs()`] for `main_first`.
+    fn spawn_and_finish<F, R, C>(mut self, main_first: bool, f: F) -> (C, R)
+    where
+        F: FnOnce() -> R,
+        T: Send + 'a,
+        C: FromIterator<T>,
+    {
+        // Each closure writes its result into its own slot. Results are only read once all
+        // threads are joined, so there is no need for channels or other synchronization.
+        let mut slots = iter::repeat_with(|| None)
+            .take(self.closures.len())
+            .collect::<Vec<Option<T>>>();
+
+        let jobs = mem::take(&mut self.closures)
+            .into_iter()
+            .zip(&mut slots)
+            .map(|(f, slot)| -> Job<'_> { Box::new(move || *slot = Some(f())) })
+            .collect();
+        let jobs = self.dispatch_order(jobs);
+
+        // Type-erase the main closure too, so that the spawning logic is not generic.
+        let mut main = Some(f);
+        let mut res = None;
+        let mut main = || res = main.take().map(|f| f());
+        if let Err(err) = spawn_jobs(jobs, &self.config, main_first, &mut main) {
+            panic::resume_unwind(err);
+        }
+
+        // Collect the results from threads.
+        let inspect = self.inspect.take();
+        let results = slots
+            .into_iter()
+            .enumerate()
+            .map(|(index, t)| {
+                let t = t.unwrap();
+                if let Some(inspect) = &inspect {
+                    inspect(index, &t);
+                }
+                t
+            })
+            .collect();
+        (results, res.unwrap())
+    }
+
+    /// Spawns a thread for each closure and runs `f` on the current thread with channels that
+    /// receive the results of closures.
+    fn spawn_with<F, R>(mut self, f: F) -> R
+    where
+        F: FnOnce(Vec<mpsc::Receiver<T>>) -> R,
+        T: Send + 'a,
+    {
+        // Channels to collect results from spawned threads.
+        let mut receivers = Vec::with_capacity(self.closures.len());
+
+        let jobs = mem::take(&mut self.closures)
+            .into_iter()
+            .map(|f| -> Job<'a> {
+                // Wrap into a closure that sends the result back. Sending fails if the main
+                // closure has dropped the receiver, in which case the result is not needed.
+                let (sender, receiver) = mpsc::channel();
+                receivers.push(receiver);
+                Box::new(move || sender.send(f()).unwrap_or(()))
+            })
+            .collect();
+        let jobs = self.dispatch_order(jobs);
+
+        // Type-erase the main closure too, so that the spawning logic is not generic.
+        let mut main = Some(move || f(receivers));
+        let mut res = None;
+        let mut main = || res = main.take().map(|f| f());
+        if let Err(err) = spawn_jobs(jobs, &self.config, false, &mut main) {
+            panic::resume_unwind(err);
+        }
+        res.unwrap()
+    }
+
+    /// Starts threads and finishes with a closure to run on the main thread that consumes results
+    /// in order as soon as they are available.
+    ///
+    /// The [`Ordered`] iterator passed to the main closure yields results in the order in which
+    /// closures were added. Each result is yielded as soon as it and all results before it are
+    /// available, so consumption overlaps with computation.
+    ///
+    /// Closures that don't get a thread of their own run on the current thread before the main
+    /// closure, though, so their results are only consumed after they have all run. This happens
+    /// when the [`limit()`][`Parallel::limit()`] or limits set with [`ParallelConfig`] apply, when
+    /// a thread cannot be spawned, and on targets without threads. So closures must not wait for
+    /// the main closure to consume results, or they will wait forever.
+    ///
+    /// If a closure panics, the iterator ends before its result, and panicking will resume in the
+    /// main thread after the main closure returns and all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let mut output = Vec::new();
+    ///
+    /// Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .finish_ordered(|results| {
+    ///         for r in results {
+    ///             output.push(r);
+    ///         }
+    ///     });
+    ///
+    /// assert_eq!(output, [10, 20, 30]);
+    /// ```
+    pub fn finish_ordered<F, R>(self, f: F) -> R
+    where
+        F: FnOnce(Ordered<'a, T>) -> R,
+        T: Send + 'a,
+    {
+        let mut parallel = self.indexed();
+        let inspect = parallel.inspect.take();
+        parallel.spawn_with(|receivers| {
+            f(Ordered {
+                receivers: receivers.into_iter(),
+                index: 0,
+                inspect,
+            })
+        })
+    }
+
+    /// Starts threads and finishes with a closure to run on the main thread that consumes results
+    /// in the order in which closures complete.
+    ///
+    /// The [`Completed`] iterator passed to the main closure yields each result as soon as its
+    /// closure completes, so results of fast closures can be processed before the slowest closure
+    /// finishes. The iterator ends once all closures have completed. Under a
+    /// [`limit()`][`Parallel::limit()`], the main closure runs before the current thread helps
+    /// with the queue.
+    ///
+    /// If a closure panics, the iterator skips its result, and panicking will resume in the main
+    /// thread after the main closure returns and all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let first = Parallel::new()
+    ///     .add(|| {
+    ///         thread::sleep(Duration::from_millis(200));
+    ///         "slow"
+    ///     })
+    ///     .add(|| "fast")
+    ///     .finish_streaming(|mut results| results.next());
+    ///
+    /// assert_eq!(first, Some("fast"));
+    /// ```
+    pub fn finish_streaming<F, R>(mut self, f: F) -> R
+    where
+        F: FnOnce(Completed<'a, T>) -> R,
+        T: Send + 'a,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let remaining = self.closures.len();
+        let inspect = self.inspect.take();
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.closures.reserve(self.closures.len());
+        for (index, f) in mem::take(&mut self.closures).into_iter().enumerate() {
+            let sender = sender.clone();
+            parallel = parallel.add(move || sender.send((index, f())).unwrap_or(()));
+        }
+        parallel.sources = mem::take(&mut self.sources);
+
+        // Drop the original sender so that the channel closes once all closures have completed.
+        drop(sender);
+        let ((), res) = parallel.indexed().spawn_and_finish(true, || {
+            f(Completed {
+                receiver,
+                overflow: Arc::default(),
+                remaining,
+                inspect,
+            })
+        });
+        res
+    }
+
+    /// Like [`finish_streaming()`][`Parallel::finish_streaming()`], but holds at most `capacity`
+    /// results that the main closure hasn't consumed yet.
+    ///
+    /// A closure that completes while the buffer is full blocks its thread until the main closure
+    /// catches up. Under a [`limit()`][`Parallel::limit()`], the blocked thread takes no further
+    /// closures from the queue, so large results are not produced faster than they are consumed.
+    /// The main closure runs before the current thread helps with the queue.
+    ///
+    /// Closures that run on the current thread before the main closure, because no thread could
+    /// be spawned for them, cannot wait for it. Their results are kept aside and yielded first.
+    /// Results of closures that complete after the main closure has returned are dropped.
+    ///
+    /// If a closure panics, the iterator skips its result, and panicking will resume in the main
+    /// thread after the main closure returns and all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let total = Parallel::new()
+    ///     .each(0..100, |i| vec![i; 1000])
+    ///     .limit(2)
+    ///     .finish_streaming_bounded(4, |results| results.map(|v| v.len()).sum::<usize>());
+    ///
+    /// assert_eq!(total, 100_000);
+    /// ```
+    pub fn finish_streaming_bounded<F, R>(mut self, capacity: usize, f: F) -> R
+    where
+        F: FnOnce(Completed<'a, T>) -> R,
+        T: Send + 'a,
+    {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let overflow = Arc::new(Mutex::new(VecDeque::new()));
+        let remaining = self.closures.len();
+        let inspect = self.inspect.take();
+        let caller = thread::current().id();
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.closures.reserve(self.closures.len());
+        for (index, f) in mem::take(&mut self.closures).into_iter().enumerate() {
+            let sender = sender.clone();
+            let overflow = overflow.clone();
+            parallel = parallel.add(move || {
+                let res = (index, f());
+                if thread::current().id() != caller {
+                    sender.send(res).unwrap_or(());
+                } else if let Err(mpsc::TrySendError::Full(res)) = sender.try_send(res) {
+                    // The main closure hasn't started yet, so don't wait for it.
+                    overflow.lock().unwrap().push_back(res);
+                }
+            });
+        }
+        parallel.sources = mem::take(&mut self.sources);
+
+        // Drop the original sender so that the channel closes once all closures have completed.
+        drop(sender);
+        let ((), res) = parallel.indexed().spawn_and_finish(true, || {
+            f(Completed {
+                receiver,
+                overflow,
+                remaining,
+                inspect,
+            })
+        });
+        res
+    }
+
+    /// Runs closures until the results collected so far satisfy a predicate, and returns those
+    /// results.
+    ///
+    /// Results are collected in the order in which closures complete, and `pred` is called on the
+    /// main thread with all results collected so far, first with none and then each time another
+    /// one arrives. Once it returns `true`, the closures that haven't started yet are skipped, like
+    /// with [`finish_cancellable()`][`Parallel::finish_cancellable()`], and the results of
+    /// closures that were still running are discarded. If `pred` never returns `true`, all
+    /// results are returned.
+    ///
+    /// Closures only wait to start under a [`limit()`][`Parallel::limit()`] or a
+    /// [`rate_limit()`][`Parallel::rate_limit()`]. Otherwise, all of them start right away, and
+    /// this returns once they have all completed. See
+    /// [`run_until_with()`][`Parallel::run_until_with()`] for stopping running closures early.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let hosts = ["a", "b", "c", "d", "e", "f", "g", "h"];
+    ///
+    /// let reachable = Parallel::new()
+    ///     .each(hosts, |host| host.len() == 1)
+    ///     .limit(2)
+    ///     .run_until(|res| res.iter().filter(|&&ok| ok).count() >= 3);
+    ///
+    /// assert_eq!(reachable, [true, true, true]);
+    /// ```
+    pub fn run_until<P>(self, pred: P) -> Vec<T>
+    where
+        P: FnMut(&[T]) -> bool,
+        T: Send + 'a,
+    {
+        self.run_until_with(&CancelToken::new(), pred)
+    }
+
+    /// Runs closures until the results collected so far satisfy a predicate, cancelling `token`
+    /// once they do, and returns those results.
+    ///
+    /// This is the same as [`run_until()`][`Parallel::run_until()`], except that closures can
+    /// hold clones of `token` and poll [`CancelToken::is_cancelled()`] to stop early once
+    /// enough results have been collected.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{CancelToken, Parallel};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let token = CancelToken::new();
+    ///
+    /// let res = Parallel::new()
+    ///     .add(|| 1)
+    ///     .add(|| {
+    ///         while !token.is_cancelled() {
+    ///             thread::sleep(Duration::from_millis(1));
+    ///         }
+    ///         2
+    ///     })
+    ///     .run_until_with(&token, |res| !res.is_empty());
+    ///
+    /// assert_eq!(res, [1]);
+    /// ```
+    pub fn run_until_with<P>(mut self, token: &CancelToken, mut pred: P) -> Vec<T>
+    where
+        P: FnMut(&[T]) -> bool,
+        T: Send + 'a,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let inspect = self.inspect.take();
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.closures.reserve(self.closures.len());
+        for (index, f) in mem::take(&mut self.closures).into_iter().enumerate() {
+            let sender = sender.clone();
+            parallel = parallel.add(move || sender.send((index, f())).unwrap_or(()));
+        }
+        parallel.sources = mem::take(&mut self.sources);
+
+        // Drop the original sender so that the channel closes once all closures have completed.
+        drop(sender);
+        let (_, collected) = parallel.finish_cancellable(token, || {
+            let mut collected = Vec::new();
+            if pred(&collected) {
+                token.cancel();
+                return collected;
+            }
+            for (index, t) in receiver {
+                if let Some(inspect) = &inspect {
+                    inspect(index, &t);
+                }
+                collected.push(t);
+                if pred(&collected) {
+                    token.cancel();
+                    break;
+                }
+            }
+            collected
+        });
+        collected
+    }
+
+    /// Runs closures until `n` of them have completed, and returns their results.
+    ///
+    /// Results are collected in the order in which closures complete. This is the same as
+    /// [`run_until()`][`Parallel::run_until()`] with a predicate that checks for `n` results, so
+    /// closures that haven't started by then are skipped and the results of closures that were
+    /// still running are discarded. If there are fewer than `n` closures, all results are
+    /// returned.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let fastest = Parallel::new()
+    ///     .each([30, 10, 20], |ms| {
+    ///         thread::sleep(Duration::from_millis(ms));
+    ///         ms
+    ///     })
+    ///     .take_first(2);
+    ///
+    /// assert_eq!(fastest.len(), 2);
+    /// ```
+    pub fn take_first(self, n: usize) -> Vec<T>
+    where
+        T: Send + 'a,
+    {
+        self.run_until(|res| res.len() >= n)
+    }
+
+    /// Runs each closure on a separate thread and sends each result with its index into a
+    /// channel as soon as it is available.
+    ///
+    /// The index is the position in which the closure was added. This lets existing consumers
+    /// receive results directly, without the results being collected first. Results sent after
+    /// the receiver has been dropped are discarded.
+    ///
+    /// This blocks until all closures have completed.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::sync::mpsc;
+    /// use std::thread;
+    ///
+    /// let (sender, receiver) = mpsc::channel();
+    /// let consumer = thread::spawn(move || {
+    ///     let mut res = receiver.iter().collect::<Vec<_>>();
+    ///     res.sort();
+    ///     res
+    /// });
+    ///
+    /// Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .send_results_to(sender);
+    ///
+    /// assert_eq!(consumer.join().unwrap(), [(0, 10), (1, 20), (2, 30)]);
+    /// ```
+    pub fn send_results_to(mut self, sender: mpsc::Sender<(usize, T)>)
+    where
+        T: Send + 'a,
+    {
+        // With an inspection hook, results go through the main thread to be inspected before they
+        // are forwarded.
+        let (sender, forward) = match self.inspect.take() {
+            None => (sender, None),
+            Some(inspect) => {
+                let (inner, receiver) = mpsc::channel();
+                (inner, Some((inspect, receiver, sender)))
+            }
+        };
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.closures.reserve(self.closures.len());
+        for (index, f) in mem::take(&mut self.closures).into_iter().enumerate() {
+            let sender = sender.clone();
+            parallel = parallel.add(move || sender.send((index, f())).unwrap_or(()));
+        }
+        parallel.sources = mem::take(&mut self.sources);
+
+        // Drop the original sender so that the channel closes once all closures have completed.
+        drop(sender);
+        parallel.finish(|| {
+            if let Some((inspect, receiver, sender)) = forward {
+                for (index, t) in receiver {
+                    inspect(index, &t);
+                    sender.send((index, t)).unwrap_or(());
+                }
+            }
+        });
+    }
+
+    /// Finishes with a closure to run on the main thread that can observe the progress of the
+    /// other closures, starts threads, and collects results.
+    ///
+    /// The [`Progress`] handle passed to the main closure reports how many of the other closures
+    /// have completed so far and can block until a number of them have completed.
+    ///
+    /// Results are collected in the order in which closures were added.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    /// A closure that panicked still counts as completed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let (res, half) = Parallel::new()
+    ///     .each(1..=4, |i| 10 * i)
+    ///     .finish_with_progress(|progress| {
+    ///         progress.wait_for(progress.total() / 2);
+    ///         progress.completed()
+    ///     });
+    ///
+    /// assert_eq!(res, [10, 20, 30, 40]);
+    /// assert!(half >= 2);
+    /// ```
+    pub fn finish_with_progress<F, R>(mut self, f: F) -> (Vec<T>, R)
+    where
+        F: FnOnce(&Progress) -> R,
+        T: Send + 'a,
+    {
+        let progress = Progress {
+            total: self.closures.len(),
+            state: Arc::new((Mutex::new(0), Condvar::new())),
+        };
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.inspect = self.inspect.take();
+        parallel.closures.reserve(self.closures.len());
+        for f in mem::take(&mut self.closures) {
+            let state = progress.state.clone();
+            parallel = parallel.add(move || {
+                // Count the closure as completed even if it panics.
+                let _guard = CompletionGuard(state);
+                f()
+            });
+        }
+        parallel.sources = mem::take(&mut self.sources);
+
+        parallel.finish(|| f(&progress))
+    }
+}
+
+impl<'a, K, T> Parallel<'a, (K, T)> {
+    /// Adds a closure to the list as a member of a group.
+    ///
+    /// Grouped closures run in the same batch as all other closures, but their results can be
+    /// collected per group with [`run_grouped()`][`Parallel::run_grouped()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .add_in_group("parse", || 1)
+    ///     .add_in_group("compress", || 2)
+    ///     .add_in_group("parse", || 3)
+    ///     .run_grouped();
+    ///
+    /// assert_eq!(res["parse"], [1, 3]);
+    /// assert_eq!(res["compress"], [2]);
+    /// ```
+    #[track_caller]
+    pub fn add_in_group<F>(self, group: K, f: F) -> Parallel<'a, (K, T)>
+    where
+        F: FnOnce() -> T + Send + 'a,
+        K: Send + 'a,
+        T: Send + 'a,
+    {
+        self.add(move || (group, f()))
+    }
+
+    /// Adds a cloned closure for each item in an iterator as members of a group.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .each_in_group("squares", 1..=3, |i| i * i)
+    ///     .each_in_group("cubes", 1..=3, |i| i * i * i)
+    ///     .run_grouped();
+    ///
+    /// assert_eq!(res["squares"], [1, 4, 9]);
+    /// assert_eq!(res["cubes"], [1, 8, 27]);
+    /// ```
+    #[track_caller]
+    pub fn each_in_group<A, I, F>(self, group: K, iter: I, f: F) -> Parallel<'a, (K, T)>
+    where
+        I: IntoIterator<Item = A>,
+        F: FnOnce(A) -> T + Clone + Send + 'a,
+        A: Send + 'a,
+        K: Clone + Send + 'a,
+        T: Send + 'a,
+    {
+        self.each(iter, move |a| (group, f(a)))
+    }
+
+    /// Runs each closure on a separate thread and collects their results per group.
+    ///
+    /// Within each group, results are collected in the order in which closures were added.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(1..=6, |i| (i % 2 == 0, i))
+    ///     .run_grouped();
+    ///
+    /// assert_eq!(res[&true], [2, 4, 6]);
+    /// assert_eq!(res[&false], [1, 3, 5]);
+    /// ```
+    pub fn run_grouped(self) -> HashMap<K, Vec<T>>
+    where
+        K: Eq + Hash + Send + 'a,
+        T: Send + 'a,
+    {
+        let mut groups = HashMap::<K, Vec<T>>::new();
+        for (k, t) in self.run() {
+            groups.entry(k).or_default().push(t);
+        }
+        groups
+    }
+}
+
+impl<'a, U, E> Parallel<'a, Result<U, E>> {
+    /// Runs each fallible closure on a separate thread and collects their results, turning panics
+    /// into errors.
+    ///
+    /// A closure that panics results in an error converted from its panic payload, and all other
+    /// results are kept. This lets batches of fallible closures integrate with `?`-style error
+    /// handling.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    /// use std::any::Any;
+    ///
+    /// #[derive(Debug)]
+    /// enum Error {
+    ///     Parse(String),
+    ///     Panic(Box<dyn Any + Send>),
+    /// }
+    ///
+    /// impl From<Box<dyn Any + Send>> for Error {
+    ///     fn from(payload: Box<dyn Any + Send>) -> Error {
+    ///         Error::Panic(payload)
+    ///     }
+    /// }
+    ///
+    /// let res = Parallel::new()
+    ///     .add(|| Ok(1))
+    ///     .add(|| Err(Error::Parse("not a number".into())))
+    ///     .add(|| panic!("bug"))
+    ///     .run_catching();
+    ///
+    /// assert!(matches!(res[0], Ok(1)));
+    /// assert!(matches!(res[1], Err(Error::Parse(_))));
+    /// assert!(matches!(res[2], Err(Error::Panic(_))));
+    /// ```
+    pub fn run_catching(self) -> Vec<Result<U, E>>
+    where
+        E: From<Box<dyn Any + Send>> + Send + 'a,
+        U: Send + 'a,
+    {
+        self.run_catching_with(E::from)
+    }
+
+    /// Runs each fallible closure on a separate thread and collects their results, turning panics
+    /// into errors with a closure.
+    ///
+    /// This is the same as [`run_catching()`][`Parallel::run_catching()`], except that `on_panic`
+    /// converts panic payloads into errors. It is called on the thread of the closure that
+    /// panicked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .add(|| Ok(1))
+    ///     .add(|| panic!("bug"))
+    ///     .run_catching_with(|_| "closure panicked");
+    ///
+    /// assert_eq!(res, [Ok(1), Err("closure panicked")]);
+    /// ```
+    pub fn run_catching_with<P>(mut self, on_panic: P) -> Vec<Result<U, E>>
+    where
+        P: Fn(Box<dyn Any + Send>) -> E + Sync,
+        E: Send + 'a,
+        U: Send + 'a,
+    {
+        let on_panic = &on_panic;
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.inspect = self.inspect.take();
+        parallel.closures.reserve(self.closures.len());
+        for f in mem::take(&mut self.closures) {
+            parallel =
+                parallel.add(move || catch_unwind(f).unwrap_or_else(|err| Err(on_panic(err))));
+        }
+        parallel.sources = mem::take(&mut self.sources);
+        parallel.run()
+    }
+
+    /// Runs each fallible closure on a separate thread and collects their results, or returns the
+    /// first error.
+    ///
+    /// All closures run to completion. If some of them fail, the error of the closure that failed
+    /// first is returned. See [`try_run_with()`][`Parallel::try_run_with()`] for stopping the
+    /// other closures early.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .each(["1", "2", "3"], |s| s.parse::<i32>())
+    ///     .try_run();
+    /// assert_eq!(res, Ok(vec![1, 2, 3]));
+    ///
+    /// let res = Parallel::new()
+    ///     .each(["1", "x", "3"], |s| s.parse::<i32>())
+    ///     .try_run();
+    /// assert!(res.is_err());
+    /// ```
+    pub fn try_run(self) -> Result<Vec<U>, E>
+    where
+        E: Send + 'a,
+        U: Send + 'a,
+    {
+        self.try_run_with(&CancelToken::new())
+    }
+
+    /// Runs each fallible closure on a separate thread and collects their results, or returns the
+    /// first error, cancelling `token` as soon as a closure fails.
+    ///
+    /// Closures can hold clones of `token` and poll [`CancelToken::is_cancelled()`] to stop early
+    /// once another closure has failed, and closures that haven't started yet by then are skipped,
+    /// like with [`run_cancellable()`][`Parallel::run_cancellable()`]. The returned error is the
+    /// one that was returned first in time, not the one of the first closure that was added, so
+    /// errors that closures return because they were cancelled don't hide the original one.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{CancelToken, Parallel};
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let token = CancelToken::new();
+    ///
+    /// let res = Parallel::new()
+    ///     .add(|| Err("disk full"))
+    ///     .each(0..3, |_| {
+    ///         for _ in 0..1000 {
+    ///             if token.is_cancelled() {
+    ///                 return Err("cancelled");
+    ///             }
+    ///             thread::sleep(Duration::from_millis(1));
+    ///         }
+    ///         Ok(())
+    ///     })
+    ///     .try_run_with(&token);
+    ///
+    /// assert_eq!(res, Err("disk full"));
+    /// ```
+    pub fn try_run_with(mut self, token: &CancelToken) -> Result<Vec<U>, E>
+    where
+        E: Send + 'a,
+        U: Send + 'a,
+    {
+        // The index of the closure that failed first.
+        let first = AtomicUsize::new(usize::MAX);
+        let first = &first;
+
+        // Only skip closures once one has failed, not when the caller cancels `token`, since there
+        // would be no error to return for them.
+        let failed = CancelToken::new();
+
+        let mut parallel = Parallel::new();
+        parallel.config = mem::take(&mut self.config);
+        parallel.inspect = self.inspect.take();
+        parallel.closures.reserve(self.closures.len());
+        for (index, f) in mem::take(&mut self.closures).into_iter().enumerate() {
+            let token = token.clone();
+            let failed = failed.clone();
+            parallel = parallel.add(move || {
+                let res = f();
+                if res.is_err() {
+                    let _ = first.compare_exchange(
+                        usize::MAX,
+                        index,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    );
+                    failed.cancel();
+                    token.cancel();
+                }
+                res
+            });
+        }
+        parallel.sources = mem::take(&mut self.sources);
+
+        let mut results = parallel.skippable(&failed).run();
+        match first.load(Ordering::SeqCst) {
+            usize::MAX => results.into_iter().map(Option::unwrap).collect(),
+            index => Err(results.swap_remove(index).unwrap().err().unwrap()),
+        }
+    }
+}
+
+impl<'a, T> Parallel<'a, Vec<T>> {
+    /// Runs each closure on a separate thread and merges their sorted results into one sorted
+    /// vector.
+    ///
+    /// Each closure must return a sorted vector. The vectors are merged pairwise in parallel with
+    /// [`tree_reduce()`], which is much faster than concatenating and sorting them again. The merge
+    /// is stable: equal items keep the order in which their closures were added.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let chunks = [vec![5, 1, 9], vec![4, 8], vec![7, 2, 3, 6]];
+    ///
+    /// let sorted = Parallel::new()
+    ///     .each(chunks, |mut chunk| {
+    ///         chunk.sort();
+    ///         chunk
+    ///     })
+    ///     .run_merge_sorted();
+    ///
+    /// assert_eq!(sorted, [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    /// ```
+    pub fn run_merge_sorted(self) -> Vec<T>
+    where
+        T: Ord + Send + 'a,
+    {
+        tree_reduce(self.run(), merge_sorted).unwrap_or_default()
+    }
+
+    /// Adds a closure that yields any number of results.
+    ///
+    /// The items yielded by the closure are collected on its thread. Use
+    /// [`run_flat()`][`Parallel::run_flat()`] to get the items of all closures in one vector.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let records = Parallel::new()
+    ///     .add_flat(|| 0..3)
+    ///     .add_flat(|| None)
+    ///     .add_flat(|| vec![10, 20])
+    ///     .run_flat();
+    ///
+    /// assert_eq!(records, [0, 1, 2, 10, 20]);
+    /// ```
+    #[track_caller]
+    pub fn add_flat<I, F>(self, f: F) -> Parallel<'a, Vec<T>>
+    where
+        F: FnOnce() -> I + Send + 'a,
+        I: IntoIterator<Item = T>,
+        T: Send + 'a,
+    {
+        self.add(move || f().into_iter().collect())
+    }
+
+    /// Runs each closure on a separate thread and concatenates their results.
+    ///
+    /// Items are returned in the order their closures were added, and in the order each closure
+    /// yielded them.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let words = Parallel::new()
+    ///     .each(["a b", "c", "d e f"], |line| {
+    ///         line.split(' ').map(String::from).collect()
+    ///     })
+    ///     .run_flat();
+    ///
+    /// assert_eq!(words, ["a", "b", "c", "d", "e", "f"]);
+    /// ```
+    pub fn run_flat(self) -> Vec<T>
+    where
+        T: Send + 'a,
+    {
+        let results = self.run();
+        let mut flat = Vec::with_capacity(results.iter().map(Vec::len).sum());
+        for v in results {
+            flat.extend(v);
+        }
+        flat
+    }
+}
+
+impl<T: Send + 'static> Parallel<'static, T> {
+    /// Spawns a thread for each closure and returns a guard that joins them when dropped.
+    ///
+    /// Unlike [`run()`][`Parallel::run()`], this returns immediately while the closures keep
+    /// running in the background, and none of them runs on the main thread. Results can be
+    /// collected with [`Running::join()`]. If the guard is dropped instead, the threads are joined
+    /// and their results discarded.
+    ///
+    /// This is only available for closures that don't borrow local variables, because nothing
+    /// prevents the guard from being leaked with [`std::mem::forget()`], in which case the threads
+    /// would outlive the variables they borrow.
+    ///
+    /// If a thread cannot be spawned, or the limits set with [`ParallelConfig`] are reached, the
+    /// remaining closures run on the current thread before this returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let running = Parallel::new()
+    ///     .each(1..=3, |i| 10 * i)
+    ///     .start();
+    ///
+    /// println!("Threads are running in the background");
+    ///
+    /// assert_eq!(running.join(), [10, 20, 30]);
+    /// ```
+    pub fn start(self) -> Running<T> {
+        let cx = Context::capture();
+        let mut parallel = self.indexed();
+        let config = mem::take(&mut parallel.config);
+        let mut closures = mem::take(&mut parallel.closures).into_iter();
+        let mut handles = Vec::with_capacity(closures.len());
+
+        if THREADS {
+            // There is at most one live thread per closure. Take a closure only once it has a live
+            // thread, so that none is lost when the depth or thread limits are reached.
+            for live in config.live_threads(&cx, closures.len()) {
+                let f = closures.next().unwrap();
+                // Keep the closure in a slot so that it is not lost if spawning fails.
+                let slot = Arc::new(Mutex::new(Some(f)));
+                let spawned = config.builder().spawn({
+                    let slot = slot.clone();
+                    let cx = cx.clone();
+                    move || {
+                        let _live = live;
+                        let f = slot.lock().unwrap().take().unwrap();
+                        cx.enter(f)
+                    }
+                });
+
+                match spawned {
+                    Ok(handle) => {
+                        config.spawned(handle.thread());
+                        handles.push(Task::Spawned(handle));
+                    }
+                    Err(err) => {
+                        config.spawn_failed(&err);
+                        let f = slot.lock().unwrap().take().unwrap();
+                        handles.push(Task::Finished(catch_unwind(|| cx.enter(f))));
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Run the closures that didn't get a thread on the current thread.
+        handles.extend(closures.map(|f| Task::Finished(catch_unwind(|| cx.enter(f)))));
+        Running {
+            handles,
+            inspect: parallel.inspect.take(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Parallel<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Parallel")
+            .field("len", &self.closures.len())
+            .finish()
+    }
+}
+
+impl<T> Default for Parallel<'_, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Settings for running closures that can be shared by many [`Parallel`] builders.
+///
+/// Build the settings once and apply them with [`Parallel::with_config()`] instead of repeating
+/// the same builder chain everywhere.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::{Parallel, ParallelConfig};
+///
+/// let config = ParallelConfig::new().name("downloader").rate_limit(100.0);
+///
+/// let names = Parallel::new()
+///     .each(0..2, |_| std::thread::current().name().map(String::from))
+///     .with_config(&config)
+///     .run();
+///
+/// // The last closure runs on the current thread.
+/// assert_eq!(names[0].as_deref(), Some("downloader"));
+/// ```
+#[derive(Clone, Default)]
+pub struct ParallelConfig {
+    /// Minimum time between the starts of consecutive closures.
+    interval: Option<Duration>,
+
+    /// Stack size of spawned threads.
+    stack_size: Option<usize>,
+
+    /// Name of spawned threads.
+    name: Option<String>,
+
+    /// Nesting depth beyond which batches don't spawn threads.
+    max_depth: Option<usize>,
+
+    /// Maximum number of running threads spawned by this crate.
+    max_threads: Option<usize>,
+
+    /// Maximum number of threads spawned for a single batch.
+    limit: Option<usize>,
+
+    /// Called when more threads would be running than there are available CPUs.
+    on_oversubscribe: Option<Arc<dyn Fn(usize, usize) + Send + Sync>>,
+
+    /// Called when a thread cannot be spawned.
+    on_spawn_error: Option<SpawnErrorHook>,
+
+    /// Called with each spawned thread.
+    on_spawn: Option<SpawnHook>,
+
+    /// Set thread-local values around each closure.
+    task_locals: Vec<TaskLocalHook>,
+
+    /// Holds back closures that haven't started while paused.
+    pause: Option<PauseToken>,
+
+    /// Whether queued closures are taken from each `add()` or `each()` call in turn.
+    fair: bool,
+}
+
+impl ParallelConfig {
+    /// Creates default settings.
+    ///
+    /// By default, closures start right away on threads with the default stack size and no name.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelConfig;
+    ///
+    /// let config = ParallelConfig::new();
+    /// ```
+    pub fn new() -> ParallelConfig {
+        ParallelConfig::default()
+    }
+
+    /// Limits how quickly closures may start to `per_second` closures per second.
+    ///
+    /// See [`Parallel::rate_limit()`] for details. Rates so low that consecutive closures would
+    /// start more than [`Duration::MAX`] apart are clamped to that interval.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `per_second` is not a positive finite number.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelConfig;
+    ///
+    /// let config = ParallelConfig::new().rate_limit(10.0);
+    /// ```
+    pub fn rate_limit(mut self, per_second: f64) -> ParallelConfig {
+        assert!(
+            per_second.is_finite() && per_second > 0.0,
+            "rate limit must be a positive finite number"
+        );
+        // Converting to a duration panics if the interval doesn't fit.
+        let secs = 1.0 / per_second;
+        self.interval = Some(if secs < u64::MAX as f64 {
+            Duration::from_secs_f64(secs)
+        } else {
+            Duration::MAX
+        });
+        self
+    }
+
+    /// Sets the stack size of spawned threads in bytes.
+    ///
+    /// The closure that runs on the current thread uses its stack instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelConfig;
+    ///
+    /// let config = ParallelConfig::new().stack_size(32 * 1024 * 1024);
+    /// ```
+    pub fn stack_size(mut self, size: usize) -> ParallelConfig {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Sets the name of spawned threads.
+    ///
+    /// The name shows up in panic messages and debuggers. The closure that runs on the current
+    /// thread keeps the name of that thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelConfig;
+    ///
+    /// let config = ParallelConfig::new().name("worker");
+    /// ```
+    pub fn name(mut self, name: impl Into<String>) -> ParallelConfig {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Limits how deeply batches can be nested before they stop spawning threads.
+    ///
+    /// A batch run outside of any closure has depth 0, and a batch run from a closure of a batch
+    /// at depth `d` has depth `d + 1`. Batches deeper than `depth` run their closures on the
+    /// current thread instead. This keeps recursive divide-and-conquer algorithms from spawning
+    /// an exponential number of threads.
+    ///
+    /// Note that the settings of the nested batch are the ones that apply. Closures that don't get
+    /// a thread run before the main closure of [`Parallel::finish()`], so they must not wait for
+    /// it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, ParallelConfig};
+    ///
+    /// fn par_sum(v: &[i32], config: &ParallelConfig) -> i32 {
+    ///     if v.len() <= 1 {
+    ///         v.iter().sum()
+    ///     } else {
+    ///         let sums = Parallel::new()
+    ///             .each(v.chunks((v.len() + 1) / 2), |c| par_sum(c, config))
+    ///             .with_config(config)
+    ///             .run();
+    ///         sums.into_iter().sum()
+    ///     }
+    /// }
+    ///
+    /// // Only the top two levels of recursion spawn threads.
+    /// let config = ParallelConfig::new().max_depth(1);
+    /// assert_eq!(par_sum(&[1, 2, 3, 4, 5, 6, 7, 8], &config), 36);
+    /// ```
+    pub fn max_depth(mut self, depth: usize) -> ParallelConfig {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Limits the number of running threads spawned by this crate.
+    ///
+    /// Threads of all batches in the process count towards the limit. Once it is reached, batches
+    /// run their remaining closures on the current thread instead of spawning more threads. These
+    /// closures run before the main closure of [`Parallel::finish()`], so they must not wait for
+    /// it.
+    ///
+    /// Threads of [`ParallelMut`], [`eager()`], and [`Parallel::add_isolated()`] count towards the
+    /// limit too. These have no settings of their own and use the process-wide default settings
+    /// from [`set_default_config()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, ParallelConfig};
+    ///
+    /// let config = ParallelConfig::new().max_threads(4);
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..100, |i| i * 2)
+    ///     .with_config(&config)
+    ///     .run();
+    ///
+    /// assert_eq!(res.len(), 100);
+    /// ```
+    pub fn max_threads(mut self, n: usize) -> ParallelConfig {
+        self.max_threads = Some(n);
+        self
+    }
+
+    /// Limits the number of threads spawned for each batch to `n`.
+    ///
+    /// See [`Parallel::limit()`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, ParallelConfig};
+    ///
+    /// let config = ParallelConfig::new().limit(4);
+    ///
+    /// let res = Parallel::new()
+    ///     .each(0..1000, |i| i * 2)
+    ///     .with_config(&config)
+    ///     .run();
+    ///
+    /// assert_eq!(res.len(), 1000);
+    /// ```
+    pub fn limit(mut self, n: usize) -> ParallelConfig {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Sets a hook that is called when running a batch would oversubscribe the CPUs.
+    ///
+    /// Before threads are spawned, the number of threads spawned by this crate that will be
+    /// running, plus the current thread, is compared to the number of
+    /// [available][thread::available_parallelism] threads, counting the threads of all batches in
+    /// the process. If there are more, the hook is called with both numbers. This is useful for
+    /// catching accidentally nested batches during development.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, ParallelConfig};
+    ///
+    /// let config = ParallelConfig::new().on_oversubscribe(|running, available| {
+    ///     eprintln!("{} threads running on {} CPUs", running, available);
+    /// });
+    ///
+    /// Parallel::new()
+    ///     .each(0..100, |i| i)
+    ///     .with_config(&config)
+    ///     .run();
+    /// ```
+    pub fn on_oversubscribe<F>(mut self, f: F) -> ParallelConfig
+    where
+        F: Fn(usize, usize) + Send + Sync + 'static,
+    {
+        self.on_oversubscribe = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets a hook that is called with the OS error whenever a thread cannot be spawned.
+    ///
+    /// When a thread cannot be spawned, no more threads are spawned for the batch, and the
+    /// closures that didn't get a thread run on the current thread instead. The batch still
+    /// completes, but with less parallelism. Spawn failures usually mean the process is running
+    /// out of resources, such as memory or its thread limit, so the hook lets services report them
+    /// to their monitoring.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, ParallelConfig};
+    ///
+    /// let config = ParallelConfig::new().on_spawn_error(|err| {
+    ///     eprintln!("failed to spawn a thread: {}", err);
+    /// });
+    ///
+    /// Parallel::new()
+    ///     .each(0..4, |i| i)
+    ///     .with_config(&config)
+    ///     .run();
+    /// ```
+    pub fn on_spawn_error<F>(mut self, f: F) -> ParallelConfig
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.on_spawn_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets a hook to call with each thread right after it is spawned.
+    ///
+    /// The hook runs on the spawning thread and gets the [`Thread`][thread::Thread] handle of the
+    /// new thread, so profilers, debuggers or watchdogs can register it. The closure running on
+    /// the current thread is not reported.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{Parallel, ParallelConfig};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let ids = Arc::new(Mutex::new(Vec::new()));
+    /// let config = ParallelConfig::new().on_spawn({
+    ///     let ids = ids.clone();
+    ///     move |thread| ids.lock().unwrap().push(thread.id())
+    /// });
+    ///
+    /// Parallel::new()
+    ///     .each(0..4, |i| i)
+    ///     .with_config(&config)
+    ///     .run();
+    ///
+    /// assert!(ids.lock().unwrap().len() <= 3);
+    /// ```
+    pub fn on_spawn<F>(mut self, f: F) -> ParallelConfig
+    where
+        F: Fn(&thread::Thread) + Send + Sync + 'static,
+    {
+        self.on_spawn = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets a thread-local value for each closure while it runs.
+    ///
+    /// See [`Parallel::task_local()`] for details.
+    pub fn task_local<L, F>(mut self, key: &'static LocalKey<RefCell<L>>, init: F) -> ParallelConfig
+    where
+        L: 'static,
+        F: Fn() -> L + Send + Sync + 'static,
+    {
+        /// Restores the previous value when dropped, even on panic.
+        struct Restore<L: 'static>(&'static LocalKey<RefCell<L>>, Option<L>);
+
+        impl<L> Drop for Restore<L> {
+            fn drop(&mut self) {
+                if let Some(prev) = self.1.take() {
+                    self.0.with(|cell| *cell.borrow_mut() = prev);
+                }
+            }
+        }
+
+        self.task_locals.push(Arc::new(move |f: &mut dyn FnMut()| {
+            let value = init();
+            let _restore = Restore(key, Some(key.with(|cell| cell.replace(value))));
+            f()
+        }));
+        self
+    }
+
+    /// Takes queued closures from each `add()` or `each()` call in turn.
+    ///
+    /// See [`Parallel::fair()`] for details.
+    pub fn fair(mut self) -> ParallelConfig {
+        self.fair = true;
+        self
+    }
+
+    /// Holds back closures that haven't started yet while the token is paused.
+    ///
+    /// See [`Parallel::pausable()`] for details.
+    pub fn pausable(mut self, token: &PauseToken) -> ParallelConfig {
+        self.pause = Some(token.clone());
+        self
+    }
+
+    /// Reports a spawned thread.
+    fn spawned(&self, thread: &thread::Thread) {
+        if let Some(hook) = &self.on_spawn {
+            hook(thread);
+        }
+    }
+
+    /// Reports a failure to spawn a thread.
+    fn spawn_failed(&self, err: &io::Error) {
+        if let Some(hook) = &self.on_spawn_error {
+            hook(err);
+        }
+    }
+
+    /// Counts up to `n` threads about to be spawned from the given context as running, calling
+    /// the oversubscription hook if needed.
+    ///
+    /// Fewer than `n` threads are counted if spawning all of them would exceed the depth or thread
+    /// limits. The closures that don't get a thread should run on the current thread instead.
+    fn live_threads(&self, cx: &Context, n: usize) -> Vec<LiveThread> {
+        if self.max_depth.map_or(false, |max| cx.depth > max) {
+            return Vec::new();
+        }
+
+        let live = (0..n)
+            .map_while(|_| LiveThread::new(self.max_threads))
+            .collect();
+        if let Some(hook) = &self.on_oversubscribe {
+            let running = LIVE_THREADS.load(Ordering::SeqCst) + 1;
+            let available = available_threads();
+            if running > available {
+                hook(running, available);
+            }
+        }
+        live
+    }
+
+    /// Spawns a scoped thread running `f` with these settings and counts it as running.
+    ///
+    /// Returns `f` back if the depth or thread limits are reached or the thread cannot be spawned,
+    /// so that it can run on the current thread instead.
+    fn spawn_scoped<'scope, F, R>(
+        &self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        cx: &Context,
+        f: F,
+    ) -> Result<thread::ScopedJoinHandle<'scope, R>, F>
+    where
+        F: FnOnce() -> R + Send + 'scope,
+        R: Send + 'scope,
+    {
+        if !THREADS {
+            return Err(f);
+        }
+        let live = match self.live_threads(cx, 1).pop() {
+            None => return Err(f),
+            Some(live) => live,
+        };
+
+        // Keep the closure in a slot so that it is not lost if spawning fails.
+        let slot = Arc::new(Mutex::new(Some(f)));
+        let spawned = self.builder().spawn_scoped(scope, {
+            let slot = slot.clone();
+            move || {
+                let _live = live;
+                let f = slot.lock().unwrap().take().unwrap();
+                f()
+            }
+        });
+
+        match spawned {
+            Ok(handle) => {
+                self.spawned(handle.thread());
+                Ok(handle)
+            }
+            Err(err) => {
+                self.spawn_failed(&err);
+                Err(slot.lock().unwrap().take().unwrap())
+            }
+        }
+    }
+
+    /// Returns how many threads a batch with these settings could spawn from the current thread.
+    fn spawnable_threads(&self) -> usize {
+        if !THREADS
+            || self
+                .max_depth
+                .map_or(false, |max| DEPTH.with(|d| d.get()) > max)
+        {
+            return 0;
+        }
+
+        let live = LIVE_THREADS.load(Ordering::SeqCst);
+        let available = self
+            .max_threads
+            .map_or(usize::MAX, |max| max.saturating_sub(live));
+        available.min(self.limit.unwrap_or(usize::MAX))
+    }
+
+    /// Returns the process-wide default settings.
+    fn global() -> ParallelConfig {
+        // Most processes never set default settings, so don't take the lock for them.
+        if !HAS_DEFAULT_CONFIG.load(Ordering::Acquire) {
+            return ParallelConfig::default();
+        }
+        let config = DEFAULT_CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+        config.clone().unwrap_or_default()
+    }
+
+    /// Returns a thread builder with these settings.
+    fn builder(&self) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        if let Some(name) = &self.name {
+            builder = builder.name(name.clone());
+        }
+        builder
+    }
+}
+
+/// A hook called with the error when a thread cannot be spawned.
+type SpawnErrorHook = Arc<dyn Fn(&io::Error) + Send + Sync>;
+
+/// A hook called with each spawned thread.
+type SpawnHook = Arc<dyn Fn(&thread::Thread) + Send + Sync>;
+
+/// A hook that runs a closure with a thread-local value set for the task.
+type TaskLocalHook = Arc<dyn Fn(&mut dyn FnMut()) + Send + Sync>;
+
+impl fmt::Debug for ParallelConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParallelConfig")
+            .field("interval", &self.interval)
+            .field("stack_size", &self.stack_size)
+            .field("name", &self.name)
+            .field("max_depth", &self.max_depth)
+            .field("max_threads", &self.max_threads)
+            .field("limit", &self.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The number of running threads spawned by this crate.
+static LIVE_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a spawned thread as running until dropped.
+struct LiveThread(());
+
+impl LiveThread {
+    /// Counts another thread as running, unless `max` threads are already running.
+    fn new(max: Option<usize>) -> Option<LiveThread> {
+        LIVE_THREADS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if max.map_or(true, |max| n < max) {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| LiveThread(()))
+    }
+}
+
+impl Drop for LiveThread {
+    fn drop(&mut self) {
+        LIVE_THREADS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The process-wide default settings, set by [`set_default_config()`].
+static DEFAULT_CONFIG: Mutex<Option<ParallelConfig>> = Mutex::new(None);
+
+/// Whether [`DEFAULT_CONFIG`] has been set.
+static HAS_DEFAULT_CONFIG: AtomicBool = AtomicBool::new(false);
+
+/// Sets the default settings for every [`Parallel`] builder in the process.
+///
+/// This lets an application configure builders created by its dependencies, for example to give
+/// their threads a recognizable name, without passing settings through their APIs. Builders
+/// created afterwards with [`Parallel::new()`] start with these settings, unless they apply
+/// others with [`Parallel::with_config()`]. [`ParallelMut`], [`eager()`], and
+/// [`Parallel::add_isolated()`] always use these settings.
+///
+/// Limits set this way apply to the batches of dependencies too. When a limit is reached, a batch
+/// runs its remaining closures on the current thread before the main closure of
+/// [`Parallel::finish()`], which deadlocks batches whose closures wait for the main closure. So
+/// set limits only if all batches in the process tolerate this.
+///
+/// The default settings can be set only once. If they have already been set, the settings are
+/// returned back as an error.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::{Parallel, ParallelConfig};
+///
+/// easy_parallel::set_default_config(ParallelConfig::new().name("app-worker")).unwrap();
+///
+/// let names = Parallel::new()
+///     .add(|| std::thread::current().name().map(String::from))
+///     .add(|| None)
+///     .run();
+///
+/// assert_eq!(names[0].as_deref(), Some("app-worker"));
+/// assert!(easy_parallel::set_default_config(ParallelConfig::new()).is_err());
+/// ```
+#[allow(clippy::result_large_err)]
+pub fn set_default_config(config: ParallelConfig) -> Result<(), ParallelConfig> {
+    let mut default = DEFAULT_CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+    if default.is_some() {
+        return Err(config);
+    }
+    *default = Some(config);
+    HAS_DEFAULT_CONFIG.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// A builder that runs the same closures in parallel any number of times.
+///
+/// Unlike [`Parallel`], closures are [`FnMut`] and are kept after running, so the whole batch can
+/// be run again. This is useful for loops that execute the same set of jobs on every iteration,
+/// like game ticks or simulation steps.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::ParallelMut;
+///
+/// let mut a = 0;
+/// let mut b = 0;
+///
+/// let mut batch = ParallelMut::new()
+///     .add(|| { a += 1; a })
+///     .add(|| { b += 10; b });
+///
+/// assert_eq!(batch.run(), [1, 10]);
+/// assert_eq!(batch.run(), [2, 20]);
+/// ```
+#[must_use]
+pub struct ParallelMut<'a, T> {
+    /// Closures to run.
+    closures: Vec<Box<dyn FnMut() -> T + Send + 'a>>,
+}
+
+impl<'a, T> ParallelMut<'a, T> {
+    /// Creates a builder for running closures in parallel repeatedly.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelMut;
+    ///
+    /// let p = ParallelMut::<()>::new();
+    /// ```
+    pub fn new() -> ParallelMut<'a, T> {
+        ParallelMut {
+            closures: Vec::new(),
+        }
+    }
+
+    /// Adds a closure to the list.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelMut;
+    ///
+    /// let mut ticks = 0;
+    ///
+    /// let mut batch = ParallelMut::new().add(|| ticks += 1);
+    /// batch.run();
+    /// batch.run();
+    /// drop(batch);
+    ///
+    /// assert_eq!(ticks, 2);
+    /// ```
+    #[allow(clippy::should_implement_trait)]
+    #[track_caller]
+    pub fn add<F>(mut self, mut f: F) -> ParallelMut<'a, T>
+    where
+        F: FnMut() -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        let location = Location::caller();
+        self.closures
+            .push(Box::new(move || at_location(location, &mut f)));
+        self
+    }
+
+    /// Adds a cloned closure for each item in an iterator.
+    ///
+    /// Each clone of the closure keeps its item and gets a clone of it as an argument on every run.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelMut;
+    ///
+    /// let mut batch = ParallelMut::new().each(1..=3, |i| 10 * i);
+    ///
+    /// assert_eq!(batch.run(), [10, 20, 30]);
+    /// assert_eq!(batch.run(), [10, 20, 30]);
+    /// ```
+    #[track_caller]
+    pub fn each<A, I, F>(mut self, iter: I, f: F) -> ParallelMut<'a, T>
+    where
+        I: IntoIterator<Item = A>,
+        F: FnMut(A) -> T + Clone + Send + 'a,
+        A: Clone + Send + 'a,
+        T: Send + 'a,
+    {
+        let location = Location::caller();
+        let iter = iter.into_iter();
+        self.closures.reserve(iter.size_hint().0);
+        for t in iter {
+            let mut f = f.clone();
+            self.closures
+                .push(Box::new(move || at_location(location, || f(t.clone()))));
+        }
+        self
+    }
+
+    /// Runs each closure on a separate thread and collects their results.
+    ///
+    /// Results are collected in the order in which closures were added. One of the closures always
+    /// runs on the main thread because there is no point in spawning an extra thread for it.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    /// The closures are kept, so the batch can still be run again afterwards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelMut;
+    ///
+    /// let mut batch = ParallelMut::new().add(|| 1).add(|| 2);
+    ///
+    /// for _ in 0..3 {
+    ///     assert_eq!(batch.run(), [1, 2]);
+    /// }
+    /// ```
+    pub fn run(&mut self) -> Vec<T>
+    where
+        T: Send + 'a,
+    {
+        // Without threads, run the closures one after another.
+        if !THREADS {
+            let total = self.closures.len();
+            let locals = ParallelConfig::global().task_locals;
+            let results = self.closures.iter_mut().enumerate().map(|(index, f)| {
+                catch_unwind(|| in_task(index, total, || with_task_locals(&locals, f)))
+            });
+            return match join_all(results.collect::<Vec<_>>()) {
+                Ok(results) => results,
+                Err(err) => panic::resume_unwind(err),
+            };
+        }
+
+        // Get the last closure.
+        let (last, rest) = match self.closures.split_last_mut() {
+            None => return Vec::new(),
+            Some(split) => split,
+        };
+
+        // Set up a new thread scope.
+        thread::scope(|scope| {
+            // Spawn threads, run the last closure on the current thread.
+            let total = rest.len() + 1;
+            let config = ParallelConfig::global();
+            let locals: Arc<[TaskLocalHook]> = config.task_locals.clone().into();
+            let cx = Context::capture();
+            let mut tasks = Vec::with_capacity(rest.len());
+            let mut rest = rest.iter_mut().enumerate();
+
+            // Spawn threads until spawning fails or a limit is reached.
+            for (index, f) in rest.by_ref() {
+                let job = {
+                    let cx = cx.clone();
+                    let locals = locals.clone();
+                    move || cx.enter(|| in_task(index, total, || with_task_locals(&locals, f)))
+                };
+                match config.spawn_scoped(scope, &cx, job) {
+                    Ok(handle) => tasks.push(Task::Spawned(handle)),
+                    Err(job) => {
+                        tasks.push(Task::Finished(catch_unwind(job)));
+                        break;
+                    }
+                }
+            }
+
+            // Run the closures that didn't get a thread on the current thread.
+            for (index, f) in rest {
+                tasks.push(Task::Finished(catch_unwind(|| {
+                    cx.enter(|| in_task(index, total, || with_task_locals(&locals, f)))
+                })));
+            }
+            let res = catch_unwind(|| {
+                cx.enter(|| in_task(total - 1, total, || with_task_locals(&locals, last)))
+            });
+
+            // Join threads and resume the last panic if there was one.
+            let mut results = match join_all(tasks.into_iter().map(|t| t.join())) {
+                Ok(results) => results,
+                Err(err) => panic::resume_unwind(err),
+            };
+
+            // If the main closure panicked, resume its panic.
+            match res {
+                Ok(r) => results.push(r),
+                Err(err) => panic::resume_unwind(err),
+            }
+            results
+        })
+    }
+
+    /// Runs the whole batch `n` times in a row and collects the results of each run.
+    ///
+    /// Each run starts only after the previous one has completed, and the results of the `i`-th
+    /// run are the `i`-th item in the returned list. Threads are spawned once and reused for all
+    /// runs, so this is cheaper than calling [`run()`][`ParallelMut::run()`] `n` times.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads of that run
+    /// are joined, and no further runs are started.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelMut;
+    ///
+    /// let mut count = 0;
+    ///
+    /// let res = ParallelMut::new()
+    ///     .add(|| 1)
+    ///     .add(|| { count += 1; count })
+    ///     .run_repeat(3);
+    ///
+    /// assert_eq!(res, [[1, 1], [1, 2], [1, 3]]);
+    /// ```
+    pub fn run_repeat(&mut self, n: usize) -> Vec<Vec<T>>
+    where
+        T: Send + 'a,
+    {
+        // Without threads, there are no threads to reuse.
+        if !THREADS {
+            return (0..n).map(|_| self.run()).collect();
+        }
+
+        // Get the last closure.
+        let (last, rest) = match self.closures.split_last_mut() {
+            None => return (0..n).map(|_| Vec::new()).collect(),
+            Some(split) => split,
+        };
+
+        // Set up a new thread scope.
+        thread::scope(|scope| {
+            let total = rest.len() + 1;
+            let config = ParallelConfig::global();
+            let locals: Arc<[TaskLocalHook]> = config.task_locals.clone().into();
+            let cx = Context::capture();
+
+            // Closures are handed to their worker threads for each run and handed back together
+            // with their results.
+            let mut closures = rest.iter_mut().map(Some).collect::<Vec<_>>();
+            let mut workers = Vec::with_capacity(closures.len());
+
+            // Spawn workers until spawning fails or a limit is reached. The remaining closures run
+            // on the current thread.
+            for index in 0..closures.len() {
+                let (job_sender, job_receiver) =
+                    mpsc::channel::<&mut Box<dyn FnMut() -> T + Send + 'a>>();
+                let (sender, receiver) = mpsc::channel();
+
+                // Run each closure handed to this thread. The thread exits once the job channel is
+                // dropped.
+                let worker = {
+                    let cx = cx.clone();
+                    let locals = locals.clone();
+                    move || {
+                        while let Ok(f) = job_receiver.recv() {
+                            let res = catch_unwind(|| {
+                                cx.enter(|| {
+                                    in_task(index, total, || with_task_locals(&locals, &mut *f))
+                                })
+                            });
+                            if sender.send((res, f)).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                };
+                match config.spawn_scoped(scope, &cx, worker) {
+                    Ok(_) => workers.push((job_sender, receiver)),
+                    Err(_) => break,
+                }
+            }
+
+            let mut runs = Vec::with_capacity(n);
+
+            for _ in 0..n {
+                for ((jobs, _), f) in workers.iter().zip(&mut closures) {
+                    jobs.send(f.take().unwrap()).unwrap();
+                }
+
+                // Run the closures without a worker and the last closure on the current thread.
+                let inline = closures[workers.len()..]
+                    .iter_mut()
+                    .zip(workers.len()..)
+                    .map(|(f, index)| {
+                        let f = f.as_mut().unwrap();
+                        catch_unwind(|| {
+                            cx.enter(|| {
+                                in_task(index, total, || with_task_locals(&locals, &mut **f))
+                            })
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let res = catch_unwind(|| {
+                    cx.enter(|| in_task(total - 1, total, || with_task_locals(&locals, &mut *last)))
+                });
+
+                // Wait for the run to complete, taking the closures back.
+                let handed_back = workers
+                    .iter()
+                    .zip(&mut closures)
+                    .map(|((_, results), f)| {
+                        let (res, g) = results.recv().unwrap();
+                        *f = Some(g);
+                        res
+                    })
+                    .collect::<Vec<_>>();
+
+                // Resume the last panic if there was one.
+                let mut results = match join_all(handed_back.into_iter().chain(inline)) {
+                    Ok(results) => results,
+                    Err(err) => panic::resume_unwind(err),
+                };
+
+                // If the main closure panicked, resume its panic.
+                match res {
+                    Ok(r) => results.push(r),
+                    Err(err) => panic::resume_unwind(err),
+                }
+                runs.push(results);
+            }
+
+            runs
+        })
+    }
+
+    /// Runs the whole batch over and over until a closure panics, at most `max_runs` times.
+    ///
+    /// This is useful for hunting rare race conditions. Returns the index of the first run that
+    /// panicked together with the panic payload, or `None` if all runs completed without a panic.
+    /// Results of the runs are discarded.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelMut;
+    ///
+    /// let mut count = 0;
+    ///
+    /// let (run, payload) = ParallelMut::new()
+    ///     .add(|| ())
+    ///     .add(|| {
+    ///         count += 1;
+    ///         assert!(count < 3, "race detected");
+    ///     })
+    ///     .run_until_panic(10)
+    ///     .unwrap();
+    ///
+    /// assert_eq!(run, 2);
+    /// assert_eq!(payload.downcast_ref::<&str>(), Some(&"race detected"));
+    /// ```
+    pub fn run_until_panic(&mut self, max_runs: usize) -> Option<(usize, Box<dyn Any + Send>)>
+    where
+        T: Send + 'a,
+    {
+        (0..max_runs).find_map(|i| catch_unwind(|| self.run()).err().map(|err| (i, err)))
+    }
+
+    /// Runs the whole batch `iterations` times and returns timing statistics for each closure.
+    ///
+    /// Runs happen like with [`run_repeat()`][`ParallelMut::run_repeat()`], so each closure runs
+    /// on the same thread every time, and all closures run side by side under the same
+    /// conditions. This is useful for comparing alternative implementations of the same task.
+    /// Statistics are returned in the order in which closures were added, and results of the
+    /// runs are discarded.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads of that run
+    /// are joined, and no further runs are started.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelMut;
+    ///
+    /// let data = (0..10_000u64).rev().collect::<Vec<_>>();
+    ///
+    /// let stats = ParallelMut::new()
+    ///     .add(|| data.iter().copied().max())
+    ///     .add(|| {
+    ///         let mut sorted = data.clone();
+    ///         sorted.sort();
+    ///         sorted.last().copied()
+    ///     })
+    ///     .bench(20);
+    ///
+    /// for (name, s) in ["iter", "sort"].iter().zip(&stats) {
+    ///     println!("{}: min {:?}, mean {:?}, p95 {:?}", name, s.min(), s.mean(), s.p95());
+    ///     assert!(s.min() <= s.mean() && s.mean() <= s.max());
+    /// }
+    /// ```
+    pub fn bench(&mut self, iterations: usize) -> Vec<BenchStats>
+    where
+        T: Send + 'a,
+    {
+        let len = self.closures.len();
+        let mut timed = ParallelMut::new();
+        for f in &mut self.closures {
+            timed = timed.add(move || {
+                let start = Instant::now();
+                drop(f());
+                start.elapsed()
+            });
+        }
+        let runs = timed.run_repeat(iterations);
+
+        (0..len)
+            .map(|index| BenchStats::new(runs.iter().map(|run| run[index]).collect()))
+            .collect()
+    }
+}
+
+impl<T> fmt::Debug for ParallelMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParallelMut")
+            .field("len", &self.closures.len())
+            .finish()
+    }
+}
+
+impl<T> Default for ParallelMut<'_, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An iterator over results in the order in which closures were added, passed to
+/// [`Parallel::finish_ordered()`].
+pub struct Ordered<'a, T> {
+    /// Channels receiving the results of the remaining closures.
+    receivers: vec::IntoIter<mpsc::Receiver<T>>,
+
+    /// The index of the next closure.
+    index: usize,
+
+    /// Called with each result before it is yielded.
+    inspect: Option<Inspect<'a, T>>,
+}
+
+impl<T> Iterator for Ordered<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.receivers.next()?.recv() {
+            Ok(t) => {
+                if let Some(inspect) = &self.inspect {
+                    inspect(self.index, &t);
+                }
+                self.index += 1;
+                Some(t)
+            }
+            Err(_) => {
+                // The closure panicked, so stop here.
+                self.receivers = Vec::new().into_iter();
+                None
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.receivers.len()))
+    }
+}
+
+impl<T> fmt::Debug for Ordered<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ordered")
+            .field("remaining", &self.receivers.len())
+            .finish()
+    }
+}
+
+/// An iterator over results in the order in which closures complete, passed to
+/// [`Parallel::finish_streaming()`] and [`Parallel::finish_streaming_bounded()`].
+pub struct Completed<'a, T> {
+    /// Channel receiving the results of closures with their indices.
+    receiver: mpsc::Receiver<(usize, T)>,
+
+    /// Results that didn't fit into a bounded channel, received before the channel.
+    overflow: Arc<Mutex<VecDeque<(usize, T)>>>,
+
+    /// The number of closures whose results have not been received yet.
+    remaining: usize,
+
+    /// Called with each result before it is yielded.
+    inspect: Option<Inspect<'a, T>>,
+}
+
+impl<T> Iterator for Completed<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        // Receiving fails once all closures have completed, including those that panicked.
+        let next = self.overflow.lock().unwrap().pop_front();
+        let (index, t) = match next {
+            Some(next) => next,
+            None => self.receiver.recv().ok()?,
+        };
+        self.remaining -= 1;
+        if let Some(inspect) = &self.inspect {
+            inspect(index, &t);
+        }
+        Some(t)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+impl<T> fmt::Debug for Completed<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Completed")
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+/// A guard for closures running in the background, returned by [`Parallel::start()`].
+///
+/// Dropping the guard blocks until all threads are joined. If a closure panicked, panicking will
+/// resume in the dropping thread, unless that thread is already panicking.
+#[must_use = "dropping `Running` immediately joins its threads"]
+pub struct Running<T> {
+    /// Join handles for spawned threads.
+    handles: Vec<Task<thread::JoinHandle<T>, T>>,
+
+    /// Called with each result as it is collected by [`Running::join()`].
+    inspect: Option<Inspect<'static, T>>,
+}
+
+impl<T> Running<T> {
+    /// Waits for all threads to complete and collects their results.
+    ///
+    /// Results are collected in the order in which closures were added.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let running = Parallel::new().add(|| 1).add(|| 2).start();
+    /// assert_eq!(running.join(), [1, 2]);
+    /// ```
+    pub fn join(mut self) -> Vec<T> {
+        let handles = mem::take(&mut self.handles);
+        match join_all(handles.into_iter().map(|t| t.join())) {
+            Ok(results) => {
+                if let Some(inspect) = &self.inspect {
+                    for (index, t) in results.iter().enumerate() {
+                        inspect(index, t);
+                    }
+                }
+                results
+            }
+            Err(err) => panic::resume_unwind(err),
+        }
+    }
+}
+
+impl<T> Drop for Running<T> {
+    fn drop(&mut self) {
+        let handles = mem::take(&mut self.handles);
+        if let Err(err) = join_all(handles.into_iter().map(|t| t.join())) {
+            if !thread::panicking() {
+                panic::resume_unwind(err);
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for Running<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Running")
+            .field("len", &self.handles.len())
+            .finish()
+    }
+}
+
+/// A batch of closures that start running as soon as they are added, passed to the builder
+/// closure of [`eager()`].
+pub struct Eager<'scope, 'env, T> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    config: ParallelConfig,
+    cx: Context,
+    handles: Vec<Task<thread::ScopedJoinHandle<'scope, T>, T>>,
+}
+
+impl<'scope, T> Eager<'scope, '_, T> {
+    /// Spawns a thread running the closure right away.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let (v, ()) = easy_parallel::eager(|batch| {
+    ///     batch.add(|| 1).add(|| 2);
+    /// });
+    ///
+    /// assert_eq!(v, [1, 2]);
+    /// ```
+    pub fn add<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let cx = self.cx.clone();
+        let locals = self.config.task_locals.clone();
+        let job = move || cx.enter(|| with_task_locals(&locals, f));
+        let task = match self.config.spawn_scoped(self.scope, &self.cx, job) {
+            Ok(handle) => Task::Spawned(handle),
+            Err(f) => Task::Finished(catch_unwind(f)),
+        };
+        self.handles.push(task);
+        self
+    }
+
+    /// Returns the number of closures added so far.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// easy_parallel::eager(|batch| {
+    ///     batch.add(|| ()).add(|| ());
+    ///     assert_eq!(batch.len(), 2);
+    /// });
+    /// ```
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Returns `true` if no closures have been added yet.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// easy_parallel::eager(|batch| {
+    ///     assert!(batch.is_empty());
+    ///     batch.add(|| ());
+    ///     assert!(!batch.is_empty());
+    /// });
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
+
+impl<T> fmt::Debug for Eager<'_, '_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Eager")
+            .field("len", &self.handles.len())
+            .finish()
+    }
+}
+
+/// Whether the target supports spawning threads.
+///
+/// If it doesn't, closures run one after another on the current thread. WebAssembly with `atomics`
+/// is left out, since targets like `wasm32-wasip1-threads` spawn threads through the runtime.
+const THREADS: bool = !cfg!(all(target_family = "wasm", not(target_feature = "atomics")));
+
+/// A closure that was spawned on a thread or, if the target doesn't support threads, has already
+/// run to completion.
+enum Task<H, T> {
+    Spawned(H),
+    Finished(thread::Result<T>),
+}
+
+impl<T> Task<thread::JoinHandle<T>, T> {
+    /// Waits for the closure to complete and returns its result.
+    fn join(self) -> thread::Result<T> {
+        match self {
+            Task::Spawned(h) => h.join(),
+            Task::Finished(res) => res,
+        }
+    }
+}
+
+impl<T> Task<thread::ScopedJoinHandle<'_, T>, T> {
+    /// Waits for the closure to complete and returns its result.
+    fn join(self) -> thread::Result<T> {
+        match self {
+            Task::Spawned(h) => h.join(),
+            Task::Finished(res) => res,
+        }
+    }
+}
+
+/// A type-erased closure to run on a spawned thread.
+type Job<'a> = Box<dyn FnOnce() + Send + 'a>;
+
+/// Spawns a thread for each job, runs `main` on the current thread, and joins the threads.
+///
+/// Under a limit, the current thread helps with the queue of jobs before running `main`. With
+/// `main_first`, it runs `main` first instead, as long as at least one thread was spawned to take
+/// jobs from the queue in the meantime, and helps once `main` returns.
+///
+/// If a thread has panicked, returns the last panic. Otherwise, if `main` has panicked, returns
+/// its panic.
+///
+/// This function is not generic so that it is compiled only once, no matter how many different
+/// closure and result types are used with [`Parallel`].
+fn spawn_jobs(
+    jobs: Vec<Job<'_>>,
+    config: &ParallelConfig,
+    main_first: bool,
+    main: &mut dyn FnMut(),
+) -> thread::Result<()> {
+    // Without threads, run the jobs first so that `main` can wait for their results.
+    if !THREADS {
+        let results = jobs
+            .into_iter()
+            .map(|job| catch_unwind(job))
+            .collect::<Vec<_>>();
+        let res = catch_unwind(main);
+        join_all(results)?;
+        return res;
+    }
+
+    // Spawned threads take their jobs from these slots. If a thread cannot be spawned, its job
+    // stays in its slot and runs on the current thread instead.
+    let slots = jobs
+        .into_iter()
+        .map(|job| Mutex::new(Some(job)))
+        .collect::<Vec<_>>();
+
+    // With a limit, a few threads share the jobs, taking the next one from a queue whenever they
+    // are done with the previous one.
+    let queued = config.limit.is_some();
+    let next = AtomicUsize::new(0);
+    let threads = config.limit.map_or(slots.len(), |n| n.min(slots.len()));
+
+    // Set up a new thread scope.
+    thread::scope(|scope| {
+        let cx = Context::capture();
+
+        // Spawn threads on the scope until spawning fails.
+        let mut handles = Vec::with_capacity(threads);
+        let live = config.live_threads(&cx, threads);
+        for (slot, live) in slots.iter().zip(live) {
+            let (cx, slots, next) = (cx.clone(), &slots, &next);
+            let spawned = config.builder().spawn_scoped(scope, move || {
+                let _live = live;
+                if queued {
+                    run_queued(slots, next, &cx)
+                } else {
+                    let job = slot.lock().unwrap().take().unwrap();
+                    catch_unwind(|| cx.enter(job))
+                }
+            });
+
+            match spawned {
+                Ok(handle) => {
+                    config.spawned(handle.thread());
+                    handles.push(handle);
+                }
+                Err(err) => {
+                    config.spawn_failed(&err);
+                    break;
+                }
+            }
+        }
+
+        // Run the jobs that didn't get a thread first, so that `main` can wait for their results.
+        // Spawned threads keep taking queued jobs while `main` runs, so those can wait.
+        let queue_later = queued && main_first && !handles.is_empty();
+        let mut rest = if queue_later {
+            Vec::new()
+        } else if queued {
+            vec![run_queued(&slots, &next, &cx)]
+        } else {
+            slots[handles.len()..]
+                .iter()
+                .map(|slot| {
+                    let job = slot.lock().unwrap().take().unwrap();
+                    catch_unwind(|| cx.enter(job))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Run the main closure on the main thread.
+        let res = catch_unwind(|| cx.enter(main));
+        if queue_later {
+            rest.push(run_queued(&slots, &next, &cx));
+        }
+
+        // Join threads and return the last panic if there was one, then the main panic.
+        join_all(
+            handles
+                .into_iter()
+                .map(|h| h.join().and_then(|res| res))
+                .chain(rest),
+        )?;
+        res
+    })
+}
+
+/// Runs jobs from the queue of slots until it is empty.
+///
+/// All jobs run even if some of them panic. Returns the last panic if there was one.
+fn run_queued(
+    slots: &[Mutex<Option<Job<'_>>>],
+    next: &AtomicUsize,
+    cx: &Context,
+) -> thread::Result<()> {
+    let mut res = Ok(());
+    while let Some(slot) = slots.get(next.fetch_add(1, Ordering::SeqCst)) {
+        let job = slot.lock().unwrap().take().unwrap();
+        if let Err(err) = catch_unwind(|| cx.enter(job)) {
+            res = Err(err);
+        }
+    }
+    res
+}
+
+/// Runs a closure and catches its panic, if any.
+///
+/// When panics abort the process, there is nothing to catch, so the closure is simply called.
+fn catch_unwind<R>(f: impl FnOnce() -> R) -> thread::Result<R> {
+    #[cfg(panic = "abort")]
+    return Ok(f());
+
+    #[cfg(not(panic = "abort"))]
+    panic::catch_unwind(panic::AssertUnwindSafe(f))
+}
+
+/// Collects the results of joined threads, or returns the last panic if there was one.
+///
+/// All results are consumed even if a thread has panicked.
+fn join_all<T>(results: impl IntoIterator<Item = thread::Result<T>>) -> thread::Result<Vec<T>> {
+    let results = results.into_iter();
+    let mut collected = Vec::with_capacity(results.size_hint().0);
+    let mut last_err = None;
+
+    for res in results {
+        match res {
+            Ok(t) => collected.push(t),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    match last_err {
+        None => Ok(collected),
+        Some(err) => Err(err),
+    }
+}
+
+/// Returns the time after the start of a rate-limited run at which the closure with the given
+/// index may start.
+fn start_slot(interval: Duration, index: usize) -> Duration {
+    // Saturate start times too far in the future to represent.
+    u32::try_from(index)
+        .ok()
+        .and_then(|index| interval.checked_mul(index))
+        .unwrap_or(Duration::MAX)
+}
+
+/// A span of time during which a closure ran.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Span {
+    /// The time at which the closure started, relative to the start of the run.
+    start: Duration,
+
+    /// The time at which the closure ended, relative to the start of the run.
+    end: Duration,
+
+    /// How long the closure waited to start after it was allowed to by the rate limit.
+    queued: Duration,
+
+    /// The thread the closure ran on, numbered in the order in which threads started their first
+    /// closure, where `0` is the thread that started the run.
+    #[cfg_attr(not(feature = "trace"), allow(dead_code))]
+    worker: usize,
+
+    /// The location where the closure was added.
+    #[cfg_attr(not(feature = "trace"), allow(dead_code))]
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_location"))]
+    location: Option<&'static Location<'static>>,
+}
+
+/// Serializes a source location as a `file:line:column` string.
+#[cfg(feature = "serde")]
+fn serialize_location<S: serde::Serializer>(
+    location: &Option<&'static Location<'static>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match location {
+        Some(location) => serializer.collect_str(location),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl Span {
+    /// Returns how long the closure ran.
+    fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+}
+
+/// Percentiles of closure durations, returned by [`Parallel::run_with_summary()`].
+///
+/// Besides how long closures ran, the summary covers how long they waited to start, counted from
+/// the time the [rate limit][`Parallel::rate_limit()`], if any, allowed them to start. High
+/// waiting times mean that closures were waiting in the queue of a
+/// [`limit()`][`Parallel::limit()`] that is too low, while high durations mean that the closures
+/// themselves are slow.
+///
+/// Percentiles use the nearest-rank method. If there were no closures, all durations are zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Summary {
+    /// The median duration.
+    p50: Duration,
+
+    /// The 90th percentile duration.
+    p90: Duration,
+
+    /// The 99th percentile duration.
+    p99: Duration,
+
+    /// The longest duration.
+    max: Duration,
+
+    /// The median waiting time.
+    queued_p50: Duration,
+
+    /// The 90th percentile waiting time.
+    queued_p90: Duration,
+
+    /// The 99th percentile waiting time.
+    queued_p99: Duration,
+
+    /// The longest waiting time.
+    queued_max: Duration,
+}
+
+impl Summary {
+    /// Summarizes the spans of closures.
+    fn new(spans: &[Span]) -> Summary {
+        let [p50, p90, p99, max] = percentiles(spans.iter().map(Span::duration).collect());
+        let [queued_p50, queued_p90, queued_p99, queued_max] =
+            percentiles(spans.iter().map(|span| span.queued).collect());
+
+        Summary {
+            p50,
+            p90,
+            p99,
+            max,
+            queued_p50,
+            queued_p90,
+            queued_p99,
+            queued_max,
+        }
+    }
+
+    /// Returns the median duration.
+    pub fn p50(&self) -> Duration {
+        self.p50
+    }
+
+    /// Returns the 90th percentile duration.
+    pub fn p90(&self) -> Duration {
+        self.p90
+    }
+
+    /// Returns the 99th percentile duration.
+    pub fn p99(&self) -> Duration {
+        self.p99
+    }
+
+    /// Returns the longest duration.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the median time closures waited to start.
+    pub fn queued_p50(&self) -> Duration {
+        self.queued_p50
+    }
+
+    /// Returns the 90th percentile of the time closures waited to start.
+    pub fn queued_p90(&self) -> Duration {
+        self.queued_p90
+    }
+
+    /// Returns the 99th percentile of the time closures waited to start.
+    pub fn queued_p99(&self) -> Duration {
+        self.queued_p99
+    }
+
+    /// Returns the longest time a closure waited to start.
+    pub fn queued_max(&self) -> Duration {
+        self.queued_max
+    }
+}
+
+/// Returns the median, 90th and 99th percentiles, and maximum of a list of durations.
+fn percentiles(mut durations: Vec<Duration>) -> [Duration; 4] {
+    durations.sort_unstable();
+    let percentile = |p| nearest_rank(&durations, p);
+
+    [
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100),
+    ]
+}
+
+/// Returns the `p`-th percentile of sorted durations using the nearest-rank method, or zero if
+/// there are none.
+fn nearest_rank(sorted: &[Duration], p: usize) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        n => sorted[(n * p + 99) / 100 - 1],
+    }
+}
+
+/// Timing statistics of a closure, returned by [`ParallelMut::bench()`].
+///
+/// Percentiles use the nearest-rank method. If the closure never ran, all durations are zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BenchStats {
+    /// The shortest duration.
+    min: Duration,
+
+    /// The mean duration.
+    mean: Duration,
+
+    /// The 95th percentile duration.
+    p95: Duration,
+
+    /// The longest duration.
+    max: Duration,
+}
+
+impl BenchStats {
+    /// Computes statistics of a list of durations.
+    fn new(mut durations: Vec<Duration>) -> BenchStats {
+        durations.sort_unstable();
+
+        let total = durations.iter().map(Duration::as_nanos).sum::<u128>();
+        let mean = match durations.len() {
+            0 => Duration::ZERO,
+            n => Duration::from_nanos((total / n as u128) as u64),
+        };
+
+        BenchStats {
+            min: durations.first().copied().unwrap_or_default(),
+            mean,
+            p95: nearest_rank(&durations, 95),
+            max: nearest_rank(&durations, 100),
+        }
+    }
+
+    /// Returns the shortest duration.
+    pub fn min(&self) -> Duration {
+        self.min
+    }
+
+    /// Returns the mean duration.
+    pub fn mean(&self) -> Duration {
+        self.mean
+    }
+
+    /// Returns the 95th percentile duration.
+    pub fn p95(&self) -> Duration {
+        self.p95
+    }
+
+    /// Returns the longest duration.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+}
+
+/// A record of when each closure ran, returned by [`Parallel::run_traced()`].
+#[cfg(feature = "trace")]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Trace {
+    /// Spans of the closures, in the order in which closures were added.
+    spans: Vec<Span>,
+}
+
+#[cfg(feature = "trace")]
+impl Trace {
+    /// Writes the trace in the Chrome trace event format.
+    ///
+    /// Each closure is a complete event named after its index, with the location where it was
+    /// added and the number of microseconds it waited to start, as reported by
+    /// [`Summary::queued_max()`] and related methods, as arguments. Closures are placed on the
+    /// track of the thread they ran on, so that threads shared by several closures, like with
+    /// [`limit()`][`Parallel::limit()`], show up as one track. Closures that ran on the calling
+    /// thread are placed on track `0`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use easy_parallel::Parallel;
+    /// use std::fs::File;
+    ///
+    /// let (_, trace) = Parallel::new()
+    ///     .each(0..4, |i| i * i)
+    ///     .run_traced();
+    ///
+    /// trace.write_json(File::create("trace.json")?)?;
+    /// # std::io::Result::Ok(())
+    /// ```
+    pub fn write_json<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        write!(w, "{{\"traceEvents\":[")?;
+        for (index, span) in self.spans.iter().enumerate() {
+            if index > 0 {
+                write!(w, ",")?;
+            }
+            write!(
+                w,
+                "{{\"name\":\"task {}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":0,\"tid\":{}",
+                index,
+                micros(span.start),
+                micros(span.duration()),
+                span.worker,
+            )?;
+            write!(w, ",\"args\":{{")?;
+            if let Some(location) = span.location {
+                // Escape the file name, which may contain backslashes on Windows.
+                let file = location.file().replace('\\', "\\\\").replace('"', "\\\"");
+                write!(
+                    w,
+                    "\"location\":\"{}:{}:{}\",",
+                    file,
+                    location.line(),
+                    location.column(),
+                )?;
+            }
+            write!(w, "\"queued\":{}}}}}", micros(span.queued))?;
+        }
+        write!(w, "]}}")
+    }
+}
+
+/// Converts a duration into fractional microseconds.
+#[cfg(feature = "trace")]
+fn micros(d: Duration) -> f64 {
+    d.as_secs_f64() * 1e6
+}
+
+/// A handle for observing the progress of closures, passed to
+/// [`Parallel::finish_with_progress()`].
+pub struct Progress {
+    /// The number of closures running on other threads.
+    total: usize,
+
+    /// The number of completed closures and a condition variable notified on each completion.
+    state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Progress {
+    /// Returns the number of closures that have completed so far.
+    pub fn completed(&self) -> usize {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Returns the total number of closures running on other threads.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Blocks until at least `k` closures have completed.
+    ///
+    /// If `k` is greater than [`total()`][`Progress::total()`], waits for all closures.
+    pub fn wait_for(&self, k: usize) {
+        let k = k.min(self.total);
+        let (lock, cvar) = &*self.state;
+        let mut completed = lock.lock().unwrap();
+        while *completed < k {
+            completed = cvar.wait(completed).unwrap();
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("completed", &self.completed())
+            .field("total", &self.total)
+            .finish()
+    }
+}
+
+/// Marks a closure as completed when dropped.
+struct CompletionGuard(Arc<(Mutex<usize>, Condvar)>);
+
+impl Drop for CompletionGuard {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.0;
+        *lock.lock().unwrap() += 1;
+        cvar.notify_all();
+    }
+}
+
+/// A flag that tells closures in a batch to stop early.
+///
+/// Clones of a token share the same flag. [`Parallel::try_run_with()`] sets it as soon as a
+/// closure returns an error, and long-running closures can poll it to bail out instead of running
+/// to completion. Batches run with [`Parallel::run_cancellable()`],
+/// [`Parallel::finish_cancellable()`], [`Parallel::run_until_with()`] or
+/// [`Parallel::run_outcomes()`] also skip the closures that haven't started by the time it is set.
+/// Any closure holding a clone can set it, so a closure can cancel the rest of its own batch.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::CancelToken;
+///
+/// let token = CancelToken::new();
+/// let clone = token.clone();
+///
+/// token.cancel();
+/// assert!(clone.is_cancelled());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    /// Whether cancellation has been requested.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Requests cancellation.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A switch that holds back closures of a batch that haven't started yet.
+///
+/// Clones of a token share the same state. While the token is paused, batches run with
+/// [`Parallel::pausable()`] don't start any more closures until it is resumed.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::PauseToken;
+///
+/// let token = PauseToken::new();
+/// let clone = token.clone();
+///
+/// token.pause();
+/// assert!(clone.is_paused());
+///
+/// token.resume();
+/// clone.wait();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PauseToken {
+    /// Whether the token is paused, and a condition variable notified when it is resumed.
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseToken {
+    /// Creates a token that is not paused.
+    pub fn new() -> PauseToken {
+        PauseToken::default()
+    }
+
+    /// Pauses the token.
+    pub fn pause(&self) {
+        *self.state.0.lock().unwrap() = true;
+    }
+
+    /// Resumes the token, waking up threads waiting for it.
+    pub fn resume(&self) {
+        let (lock, cvar) = &*self.state;
+        *lock.lock().unwrap() = false;
+        cvar.notify_all();
+    }
+
+    /// Returns `true` if the token is paused.
+    pub fn is_paused(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Blocks the current thread while the token is paused.
+    pub fn wait(&self) {
+        let (lock, cvar) = &*self.state;
+        let mut paused = lock.lock().unwrap();
+        while *paused {
+            paused = cvar.wait(paused).unwrap();
+        }
+    }
+}
+
+/// What happened to a closure, returned by [`Parallel::run_outcomes()`].
+#[derive(Debug)]
+pub enum Outcome<T> {
+    /// The closure returned a result.
+    Completed(T),
+
+    /// The closure panicked with the given payload.
+    Panicked(Box<dyn Any + Send>),
+
+    /// The closure was skipped because the batch was cancelled before it started.
+    Cancelled,
+
+    /// The closure was skipped because the time budget ran out before it started.
+    TimedOut,
+}
+
+impl<T> Outcome<T> {
+    /// Returns `true` if the closure returned a result.
+    pub fn is_completed(&self) -> bool {
+        matches!(self, Outcome::Completed(_))
+    }
+
+    /// Returns the result of the closure, if it returned one.
+    pub fn completed(self) -> Option<T> {
+        match self {
+            Outcome::Completed(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+/// An error returned by [`Parallel::run_consensus()`] when results are not all equal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disagreement<T> {
+    /// Distinct results and the indices of closures that returned them.
+    groups: Vec<(T, Vec<usize>)>,
+}
+
+impl<T> Disagreement<T> {
+    /// Returns the distinct results, each with the indices of the closures that returned it.
+    ///
+    /// Groups are ordered by the index of the first closure that returned their result.
+    pub fn groups(&self) -> &[(T, Vec<usize>)] {
+        &self.groups
+    }
+
+    /// Converts the error into the distinct results and the indices of closures that returned them.
+    pub fn into_groups(self) -> Vec<(T, Vec<usize>)> {
+        self.groups
+    }
+}
+
+impl<T> fmt::Display for Disagreement<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.groups.is_empty() {
+            write!(f, "no results to agree on")
+        } else {
+            write!(
+                f,
+                "closures returned {} different results",
+                self.groups.len()
+            )
+        }
+    }
+}
+
+impl<T: fmt::Debug> error::Error for Disagreement<T> {}
+
+thread_local! {
+    /// The task running on the current thread.
+    static CURRENT_TASK: Cell<Option<TaskInfo>> = const { Cell::new(None) };
+
+    /// The nesting depth of batches on the current thread.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Information about a running closure, passed to [`Parallel::add_with_info()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    /// The position in which the closure was added to its batch.
+    index: usize,
+
+    /// The number of closures in the batch.
+    total: usize,
+
+    /// The location where the closure was added.
+    location: Option<&'static Location<'static>>,
+}
+
+impl TaskInfo {
+    /// Returns the position in which the closure was added to its batch.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the number of closures in the batch.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the location in the source code where the closure was added, if known.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::Parallel;
+    ///
+    /// let res = Parallel::new()
+    ///     .add_with_info(|info| info.location().unwrap().line())
+    ///     .run();
+    ///
+    /// assert_eq!(res, [line!() - 3]);
+    /// ```
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.location
+    }
+
+    /// Returns information about the closure running on the current thread.
+    ///
+    /// Returns `None` if called outside of a closure run by this crate. This is useful in a panic
+    /// hook for reporting which closure panicked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::TaskInfo;
+    /// use std::panic;
+    ///
+    /// let default_hook = panic::take_hook();
+    /// panic::set_hook(Box::new(move |info| {
+    ///     if let Some(location) = TaskInfo::current().and_then(|task| task.location()) {
+    ///         eprintln!("closure added at {} panicked", location);
+    ///     }
+    ///     default_hook(info);
+    /// }));
+    /// ```
+    pub fn current() -> Option<TaskInfo> {
+        CURRENT_TASK.with(|c| c.get())
+    }
+}
+
+/// Returns the index of the closure running on the current thread.
+///
+/// The index is the position in which the closure was added to its [`Parallel`] or
+/// [`ParallelMut`] builder. This lets shared helpers, like logging or metrics, tag their output
+/// without passing the index through every function call.
+///
+/// Returns `None` if called outside of a closure run by this crate. When batches are nested, the
+/// index of the innermost closure is returned.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::{current_task_index, Parallel};
+///
+/// fn log(msg: &str) -> String {
+///     match current_task_index() {
+///         Some(i) => format!("[task {}] {}", i, msg),
+///         None => msg.to_string(),
+///     }
+/// }
+///
+/// let res = Parallel::new()
+///     .each(0..3, |_| log("working"))
+///     .run();
+///
+/// assert_eq!(res, ["[task 0] working", "[task 1] working", "[task 2] working"]);
+/// assert_eq!(log("done"), "done");
+/// ```
+pub fn current_task_index() -> Option<usize> {
+    TaskInfo::current().map(|info| info.index)
+}
+
+/// Context of the spawning thread that is entered on spawned threads.
+#[derive(Clone)]
+struct Context {
+    /// The nesting depth of batches on the spawning thread.
+    depth: usize,
+
+    /// The `tracing` dispatcher of the spawning thread.
+    #[cfg(feature = "tracing")]
+    dispatch: tracing::Dispatch,
+
+    /// The current `tracing` span of the spawning thread.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Context {
+    /// Captures the context of the current thread.
+    fn capture() -> Context {
+        Context {
+            depth: DEPTH.with(|d| d.get()),
+            #[cfg(feature = "tracing")]
+            dispatch: tracing::dispatcher::get_default(|d| d.clone()),
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+        }
+    }
+
+    /// Runs a closure of a batch started in this context.
+    fn enter<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.enter_at(self.depth + 1, f)
+    }
+
+    /// Runs a closure on another thread as if it was running on the thread of this context.
+    fn resume<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.enter_at(self.depth, f)
+    }
+
+    /// Runs a closure inside this context at the given nesting depth.
+    fn enter_at<R>(&self, depth: usize, f: impl FnOnce() -> R) -> R {
+        /// Restores the previous depth when dropped, even on panic.
+        struct Restore(usize);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                DEPTH.with(|d| d.set(self.0));
+            }
+        }
+
+        let _restore = Restore(DEPTH.with(|d| d.replace(depth)));
+
+        #[cfg(feature = "tracing")]
+        let f = || tracing::dispatcher::with_default(&self.dispatch, || self.span.in_scope(f));
+        f()
+    }
+}
+
+/// Runs a closure as the task with the given index, restoring the previous task afterwards.
+fn in_task<R>(index: usize, total: usize, f: impl FnOnce() -> R) -> R {
+    /// Restores the previous task when dropped, even on panic.
+    struct Restore(Option<TaskInfo>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT_TASK.with(|c| c.set(self.0));
+        }
+    }
+
+    let info = TaskInfo {
+        index,
+        total,
+        location: None,
+    };
+    let _restore = Restore(CURRENT_TASK.with(|c| c.replace(Some(info))));
+    f()
+}
+
+/// Runs a closure with the thread-local values set by the given hooks.
+fn with_task_locals<R>(hooks: &[TaskLocalHook], f: impl FnOnce() -> R) -> R {
+    match hooks.split_first() {
+        None => f(),
+        Some((hook, rest)) => {
+            let mut f = Some(f);
+            let mut res = None;
+            hook(&mut || res = Some(with_task_locals(rest, f.take().unwrap())));
+            res.unwrap()
+        }
+    }
+}
+
+/// Runs a closure added at the given location, recording the location in the current task.
+///
+/// The previous task, if any, is restored by [`in_task()`] once the closure has run.
+fn at_location<R>(location: &'static Location<'static>, f: impl FnOnce() -> R) -> R {
+    CURRENT_TASK.with(|c| {
+        if let Some(mut info) = c.get() {
+            info.location = Some(location);
+            c.set(Some(info));
+        }
+    });
+    f()
+}
+
+/// Runs closures that stream items over a channel while consuming the items on the main thread.
+///
+/// `build` receives the sending side of a channel and returns the closures to run, which can
+/// clone the sender to emit any number of items while they run. Meanwhile, `consume` runs on the
+/// main thread with the receiving side. The channel is closed once all closures have completed,
+/// so `consume` can simply iterate over the receiver.
+///
+/// As with [`Parallel::finish()`], closures that don't get a thread of their own run before
+/// `consume`, so they must not wait for it.
+///
+/// Returns the results of the closures in the order in which they were added, and the result of
+/// `consume`.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::Parallel;
+///
+/// let texts = ["one two", "three", "four five six"];
+///
+/// let (counts, words) = easy_parallel::streaming(
+///     |tx| {
+///         Parallel::new().each(texts, move |text| {
+///             for word in text.split(' ') {
+///                 tx.send(word).unwrap();
+///             }
+///             text.len()
+///         })
+///     },
+///     |rx| rx.iter().count(),
+/// );
+///
+/// assert_eq!(counts, [7, 5, 13]);
+/// assert_eq!(words, 6);
+/// ```
+pub fn streaming<'a, T, U, B, F, R>(build: B, consume: F) -> (Vec<T>, R)
+where
+    B: FnOnce(mpsc::Sender<U>) -> Parallel<'a, T>,
+    F: FnOnce(mpsc::Receiver<U>) -> R,
+    T: Send + 'a,
+{
+    // The sender is moved into `build`, so only the closures keep it alive.
+    let (sender, receiver) = mpsc::channel();
+    build(sender).finish(|| consume(receiver))
+}
+
+/// Builds a batch of closures that start running as soon as they are added.
+///
+/// Unlike [`Parallel`], which spawns threads only once the whole batch has been built, every
+/// closure added to the [`Eager`] batch gets its own thread right away. This way, long-running
+/// closures added early make progress while `build` is still producing the rest, for example from
+/// a slow source.
+///
+/// Returns the results of the closures in the order in which they were added, and the result of
+/// `build`.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::mpsc;
+///
+/// let (tx, rx) = mpsc::channel();
+/// for i in 1..=3 {
+///     tx.send(i).unwrap();
+/// }
+/// drop(tx);
+///
+/// let (squares, count) = easy_parallel::eager(|batch| {
+///     // Each item starts being processed as soon as it is received.
+///     for i in rx {
+///         batch.add(move || i * i);
+///     }
+///     batch.len()
+/// });
+///
+/// assert_eq!(squares, [1, 4, 9]);
+/// assert_eq!(count, 3);
+/// ```
+pub fn eager<'env, T, B, R>(build: B) -> (Vec<T>, R)
+where
+    B: for<'scope> FnOnce(&mut Eager<'scope, 'env, T>) -> R,
+{
+    thread::scope(|scope| {
+        let mut batch = Eager {
+            scope,
+            config: ParallelConfig::global(),
+            cx: Context::capture(),
+            handles: Vec::new(),
+        };
+
+        // Build the batch on the main thread while the added closures are already running.
+        let res = catch_unwind(|| build(&mut batch));
+
+        // Join threads and resume the last panic if there was one.
+        let results = join_all(batch.handles.into_iter().map(|t| t.join()))
+            .unwrap_or_else(|err| panic::resume_unwind(err));
+
+        // If the builder panicked, resume its panic.
+        match res {
+            Ok(r) => (results, r),
+            Err(err) => panic::resume_unwind(err),
+        }
+    })
+}
+
+/// Runs a closure repeatedly on multiple threads until one of its results satisfies a predicate.
+///
+/// A clone of the closure runs in a loop on each of the [available][thread::available_parallelism]
+/// threads. As soon as one of the results satisfies `pred`, the other threads stop after finishing
+/// their current attempt. Returns the matching result together with the total number of attempts
+/// made by all threads.
+///
+/// If no result ever satisfies the predicate, this function never returns.
+///
+/// If a closure panics, the other threads stop and panicking will resume in the main thread after
+/// all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::atomic::{AtomicU64, Ordering};
+///
+/// let nonce = AtomicU64::new(0);
+///
+/// let (n, attempts) = easy_parallel::repeat_until(
+///     || nonce.fetch_add(1, Ordering::SeqCst),
+///     |n| n % 1000 == 999,
+/// );
+///
+/// assert_eq!(n % 1000, 999);
+/// assert!(attempts >= 1000);
+/// ```
+pub fn repeat_until<'a, T, F, P>(f: F, pred: P) -> (T, usize)
+where
+    F: FnMut() -> T + Clone + Send + 'a,
+    P: Fn(&T) -> bool + Sync + 'a,
+    T: Send + 'a,
+{
+    let stop = AtomicBool::new(false);
+    let attempts = AtomicUsize::new(0);
+
+    let (stop, attempts, pred) = (&stop, &attempts, &pred);
+    let results = Parallel::new()
+        .each(0..available_threads(), move |_| {
+            let mut f = f;
+            let search = || {
+                while !stop.load(Ordering::SeqCst) {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    let t = f();
+
+                    // Only the first thread to find a match gets to report it.
+                    if pred(&t) && !stop.swap(true, Ordering::SeqCst) {
+                        return Some(t);
+                    }
+                }
+                None
+            };
+
+            // Stop the other threads if this one panics.
+            catch_unwind(search).unwrap_or_else(|err| {
+                stop.store(true, Ordering::SeqCst);
+                panic::resume_unwind(err)
+            })
+        })
+        .run();
+
+    let t = results.into_iter().flatten().next().unwrap();
+    (t, attempts.load(Ordering::SeqCst))
+}
+
+/// Runs `n` independent trials on multiple threads and combines their outputs.
+///
+/// Trials are numbered from `0` to `n - 1` and each trial receives its number, which can be used
+/// to seed a random number generator so that runs are reproducible. The trials are split into
+/// contiguous chunks, one per [available][thread::available_parallelism] thread, and each thread
+/// combines the outputs of its own trials before the per-thread outputs are combined with
+/// [`tree_reduce()`]. Since the grouping depends on the number of threads, `combine` should be
+/// associative.
+///
+/// Returns `None` if `n` is zero.
+///
+/// If a trial panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// // Roll a "die" seeded by the trial number and count the sixes.
+/// let sixes = easy_parallel::par_trials(
+///     6000,
+///     |i| usize::from((i * 7919 + 13) % 6 == 5),
+///     |a, b| a + b,
+/// );
+///
+/// assert_eq!(sixes, Some(1000));
+/// ```
+pub fn par_trials<'a, R, F, C>(n: usize, trial: F, combine: C) -> Option<R>
+where
+    F: Fn(usize) -> R + Sync + 'a,
+    C: Fn(R, R) -> R + Sync + 'a,
+    R: Send + 'a,
+{
+    let threads = available_threads().min(n);
+    let (trial, combine) = (&trial, &combine);
+
+    let combined = Parallel::new()
+        .each(0..threads, move |i| {
+            chunk_range(n, threads, i).map(trial).reduce(combine)
+        })
+        .run();
+
+    tree_reduce(combined.into_iter().flatten(), combine)
+}
+
+/// Maps the items of an iterator in parallel and reduces the mapped values into one.
+///
+/// Items are split into contiguous chunks, one per [available][thread::available_parallelism]
+/// thread. Each thread maps and reduces its own chunk, and the per-thread values are then reduced
+/// with [`tree_reduce()`]. Since the grouping depends on the number of threads, `reduce` should be
+/// associative.
+///
+/// Returns `None` if the iterator is empty.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// let words = ["apple", "kiwi", "banana"];
+///
+/// let total = easy_parallel::map_reduce(words, |w| w.len(), |a, b| a + b);
+/// assert_eq!(total, Some(15));
+/// ```
+pub fn map_reduce<I, U, M, R>(iter: I, map: M, reduce: R) -> Option<U>
+where
+    I: IntoIterator,
+    I::Item: Send,
+    M: Fn(I::Item) -> U + Sync,
+    R: Fn(U, U) -> U + Sync,
+    U: Send,
+{
+    let (map, reduce) = (&map, &reduce);
+
+    let reduced = Parallel::new()
+        .each(split_into_chunks(iter), move |chunk| {
+            chunk.into_iter().map(map).reduce(reduce)
+        })
+        .run();
+
+    tree_reduce(reduced.into_iter().flatten(), reduce)
+}
+
+/// Folds the items of an iterator into per-thread accumulators and combines them into one.
+///
+/// Items are split into contiguous chunks, one per [available][thread::available_parallelism]
+/// thread. Each thread starts from its own accumulator created by `init` and folds every item of
+/// its chunk into it, so no value is allocated or sent per item. The per-thread accumulators are
+/// then combined with [`tree_reduce()`]. Since the grouping depends on the number of threads,
+/// `combine` should be associative and `init` should return an identity value.
+///
+/// Returns `init()` if the iterator is empty.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let words = ["apple", "kiwi", "avocado", "banana", "blueberry"];
+///
+/// let by_letter = easy_parallel::fold_with(
+///     words,
+///     HashMap::new,
+///     |mut counts, w| {
+///         *counts.entry(w.as_bytes()[0]).or_insert(0) += 1;
+///         counts
+///     },
+///     |mut a, b| {
+///         for (k, n) in b {
+///             *a.entry(k).or_insert(0) += n;
+///         }
+///         a
+///     },
+/// );
+///
+/// assert_eq!(by_letter[&b'a'], 2);
+/// assert_eq!(by_letter[&b'b'], 2);
+/// assert_eq!(by_letter[&b'k'], 1);
+/// ```
+pub fn fold_with<I, A, N, F, C>(iter: I, init: N, fold: F, combine: C) -> A
+where
+    I: IntoIterator,
+    I::Item: Send,
+    N: Fn() -> A + Sync,
+    F: Fn(A, I::Item) -> A + Sync,
+    C: Fn(A, A) -> A + Sync,
+    A: Send,
+{
+    let (init, fold) = (&init, &fold);
+
+    let folded = Parallel::new()
+        .each(split_into_chunks(iter), move |chunk| {
+            chunk.into_iter().fold(init(), fold)
+        })
+        .run();
+
+    tree_reduce(folded, combine).unwrap_or_else(init)
+}
+
+/// Reduces items into one by combining adjacent pairs in parallel.
+///
+/// In each round, adjacent pairs of items are combined in parallel on the
+/// [available][thread::available_parallelism] threads, halving the number of items, until only
+/// one item is left. This is faster than folding serially when `op` is expensive, like when
+/// merging large maps or concatenating big buffers. The order of items is preserved, so `op` only
+/// needs to be associative.
+///
+/// Returns `None` if there are no items.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// let parts = vec![vec![1, 2], vec![3], vec![4, 5], vec![6]];
+///
+/// let all = easy_parallel::tree_reduce(parts, |mut a, b| {
+///     a.extend(b);
+///     a
+/// });
+///
+/// assert_eq!(all, Some(vec![1, 2, 3, 4, 5, 6]));
+/// ```
+pub fn tree_reduce<I, F>(items: I, op: F) -> Option<I::Item>
+where
+    I: IntoIterator,
+    I::Item: Send,
+    F: Fn(I::Item, I::Item) -> I::Item + Sync,
+{
+    let mut items = items.into_iter().collect::<Vec<_>>();
+
+    while items.len() > 1 {
+        // An odd item out is carried over to the next round.
+        let odd = if items.len() % 2 == 1 {
+            items.pop()
+        } else {
+            None
+        };
+
+        let mut iter = items.into_iter();
+        let mut pairs = Vec::with_capacity(iter.len() / 2);
+        while let (Some(a), Some(b)) = (iter.next(), iter.next()) {
+            pairs.push((a, b));
+        }
+
+        items = pairs
+            .into_iter()
+            .parallel_map(available_threads(), |(a, b)| op(a, b));
+        items.extend(odd);
+    }
+
+    items.pop()
+}
+
+/// Merges two sorted vectors into one, taking items from `a` first when they are equal.
+fn merge_sorted<T: Ord>(a: Vec<T>, b: Vec<T>) -> Vec<T> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+
+    while let (Some(x), Some(y)) = (a.peek(), b.peek()) {
+        if y < x {
+            merged.extend(b.next());
+        } else {
+            merged.extend(a.next());
+        }
+    }
+
+    merged.extend(a);
+    merged.extend(b);
+    merged
+}
+
+/// Collects items into contiguous chunks, one per available thread.
+fn split_into_chunks<I: IntoIterator>(iter: I) -> Vec<Vec<I::Item>> {
+    let mut items = iter.into_iter().collect::<Vec<_>>();
+    let len = items.len();
+    let parts = available_threads().min(len);
+
+    // Split off chunks from the back, so that each item is moved only once.
+    let mut chunks = (0..parts)
+        .rev()
+        .map(|i| items.split_off(chunk_range(len, parts, i).start))
+        .collect::<Vec<_>>();
+    chunks.reverse();
+    chunks
+}
+
+/// Runs a cloned closure on items of an iterator in parallel until a time budget is exhausted.
+///
+/// Items are taken from the iterator in rounds of up to one per
+/// [available][thread::available_parallelism] thread. New rounds start until the budget is
+/// exhausted or the iterator runs out of items. A round that has started always runs to
+/// completion, so this may take longer than the budget. A budget of [`Duration::MAX`] processes
+/// all items.
+///
+/// Returns the results for the processed items in order, together with the iterator holding the
+/// items that were not processed. This is useful for best-effort passes that can be skipped or
+/// resumed later.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let (done, rest) = easy_parallel::each_within(0..1000, Duration::from_millis(10), |i| i * 2);
+///
+/// // Items are processed in order, and the remainder picks up where processing stopped.
+/// assert!(done.iter().copied().eq((0..done.len()).map(|i| i * 2)));
+/// assert_eq!(rest.len(), 1000 - done.len());
+/// ```
+pub fn each_within<I, T, F>(iter: I, budget: Duration, f: F) -> (Vec<T>, I::IntoIter)
+where
+    I: IntoIterator,
+    I::Item: Send,
+    F: FnOnce(I::Item) -> T + Clone + Send,
+    T: Send,
+{
+    // A budget too large to represent a point in time means there is no deadline.
+    let deadline = Instant::now().checked_add(budget);
+    let mut iter = iter.into_iter();
+    let mut results = Vec::new();
+
+    while deadline.map_or(true, |deadline| Instant::now() < deadline) {
+        let round = Parallel::new()
+            .each(iter.by_ref().take(available_threads()), f.clone())
+            .run();
+        if round.is_empty() {
+            break;
+        }
+        results.extend(round);
+    }
+
+    (results, iter)
+}
+
+/// Runs a closure on keyed items in parallel, processing items with the same key on the same
+/// thread.
+///
+/// Items are grouped by their key, which acts as an affinity group. Each group is assigned to one
+/// thread, which processes the items of the group one after another in the order in which they
+/// appear, so that they can share warm caches. Different groups run in parallel. This is useful
+/// for per-shard processing.
+///
+/// At most one thread per available core is spawned. When there are more groups than that, they
+/// are spread over the threads in the order in which their keys first appear, and a thread
+/// processes the items of all its groups in order.
+///
+/// Returns the results in the order of the items.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// let writes = [("shard-a", 1), ("shard-b", 2), ("shard-a", 3)];
+///
+/// let res = easy_parallel::each_with_affinity(writes, |x| x * 10);
+/// assert_eq!(res, [10, 20, 30]);
+/// ```
+pub fn each_with_affinity<K, A, T, I, F>(items: I, f: F) -> Vec<T>
+where
+    I: IntoIterator<Item = (K, A)>,
+    K: Eq + Hash,
+    A: Send,
+    T: Send,
+    F: Fn(A) -> T + Sync,
+{
+    // Put items into one bucket per thread by key, remembering the position of each item.
+    let threads = available_threads();
+    let mut groups = HashMap::new();
+    let mut buckets: Vec<Vec<(usize, A)>> = Vec::new();
+    let mut len = 0;
+    for (index, (key, item)) in items.into_iter().enumerate() {
+        let next = groups.len() % threads;
+        let bucket = *groups.entry(key).or_insert(next);
+        if bucket == buckets.len() {
+            buckets.push(Vec::new());
+        }
+        buckets[bucket].push((index, item));
+        len += 1;
+    }
+
+    let f = &f;
+    let processed = Parallel::new()
+        .each(buckets, move |bucket| {
+            bucket
+                .into_iter()
+                .map(|(index, item)| (index, f(item)))
+                .collect::<Vec<_>>()
+        })
+        .run();
+
+    // Put results back into the order of the items.
+    let mut results = iter::repeat_with(|| None).take(len).collect::<Vec<_>>();
+    for (index, t) in processed.into_iter().flatten() {
+        results[index] = Some(t);
+    }
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Runs a closure on items in parallel, lending each running closure a resource from a pool.
+///
+/// Each resource in `pool`, like a database connection or an API token, gets its own thread,
+/// which takes items one at a time and calls `f` with exclusive access to the resource. So at most
+/// `pool.len()` closures run at once, and a resource is handed to the next item as soon as the
+/// previous one is done with it.
+///
+/// Returns the results in the order of the items.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Panics
+///
+/// Panics if `pool` is empty and there are items to process.
+///
+/// # Examples
+///
+/// ```
+/// struct Connection {
+///     queries: usize,
+/// }
+///
+/// let mut pool = [Connection { queries: 0 }, Connection { queries: 0 }];
+///
+/// let rows = easy_parallel::each_with_resource(&mut pool, 1..=5, |conn, id| {
+///     conn.queries += 1;
+///     id * 100
+/// });
+///
+/// assert_eq!(rows, [100, 200, 300, 400, 500]);
+/// assert_eq!(pool.iter().map(|c| c.queries).sum::<usize>(), 5);
+/// ```
+pub fn each_with_resource<R, A, T, I, F>(pool: &mut [R], items: I, f: F) -> Vec<T>
+where
+    I: IntoIterator<Item = A>,
+    R: Send,
+    A: Send,
+    T: Send,
+    F: Fn(&mut R, A) -> T + Sync,
+{
+    let items = items.into_iter().collect::<Vec<_>>();
+    let len = items.len();
+    assert!(
+        len == 0 || !pool.is_empty(),
+        "`each_with_resource` needs at least one resource"
+    );
+
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let (queue, f) = (&queue, &f);
+    let processed = Parallel::new()
+        .each_mut(pool.iter_mut().take(len), move |resource| {
+            let mut processed = Vec::new();
+            while let Some((index, item)) = queue.lock().unwrap().next() {
+                processed.push((index, f(resource, item)));
+            }
+            processed
+        })
+        .run();
+
+    // Put results back into the order of the items.
+    let mut results = iter::repeat_with(|| None).take(len).collect::<Vec<_>>();
+    for (index, t) in processed.into_iter().flatten() {
+        results[index] = Some(t);
+    }
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Creates a vector by calling a closure on each index in parallel.
+///
+/// The `i`-th element of the vector is `f(i)`. Indices are split into contiguous chunks, one per
+/// [available][thread::available_parallelism] thread, so this is suited for building large lookup
+/// tables or images.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// let squares = easy_parallel::par_from_fn(5, |i| i * i);
+/// assert_eq!(squares, [0, 1, 4, 9, 16]);
+/// ```
+pub fn par_from_fn<T, F>(len: usize, f: F) -> Vec<T>
+where
+    F: Fn(usize) -> T + Sync,
+    T: Send,
+{
+    let parts = available_threads().min(len);
+    let f = &f;
+
+    let chunks = Parallel::new()
+        .each(0..parts, move |i| {
+            chunk_range(len, parts, i).map(f).collect::<Vec<_>>()
+        })
+        .run();
+
+    let mut v = Vec::with_capacity(len);
+    for chunk in chunks {
+        v.extend(chunk);
+    }
+    v
+}
+
+/// Calls a closure on each point of a two-dimensional range in parallel.
+///
+/// The closure is called as `f(y, x)` for every `y` in `rows` and `x` in `cols`, and the results
+/// are returned row by row. Rows are split into contiguous bands, one per
+/// [available][thread::available_parallelism] thread, so each thread works on whole rows rather
+/// than on single points. This is suited for image processing and grid simulations.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// let table = easy_parallel::each_2d(1..4, 1..4, |y, x| y * x);
+///
+/// assert_eq!(table, [[1, 2, 3], [2, 4, 6], [3, 6, 9]]);
+/// ```
+pub fn each_2d<T, F>(rows: Range<usize>, cols: Range<usize>, f: F) -> Vec<Vec<T>>
+where
+    F: Fn(usize, usize) -> T + Sync,
+    T: Send,
+{
+    let len = rows.len();
+    let parts = available_threads().min(len);
+    let (f, cols) = (&f, &cols);
+
+    let bands = Parallel::new()
+        .each(0..parts, move |i| {
+            let band = chunk_range(len, parts, i);
+            (rows.start + band.start..rows.start + band.end)
+                .map(|y| cols.clone().map(|x| f(y, x)).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        })
+        .run();
+
+    let mut v = Vec::with_capacity(len);
+    for band in bands {
+        v.extend(band);
+    }
+    v
+}
+
+/// Computes the inclusive prefix scan of a slice in parallel.
+///
+/// The `i`-th element of the result is `slice[0]` combined with all following elements up to and
+/// including `slice[i]` using `op`. The operation must be associative.
+///
+/// This uses the classic two-pass algorithm: the slice is split into contiguous chunks, one per
+/// [available][thread::available_parallelism] thread, that are scanned in parallel. Then the
+/// totals of preceding chunks are combined on the main thread and applied to each chunk in
+/// parallel.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// let sums = easy_parallel::par_scan(&[1, 2, 3, 4, 5], |a, b| a + b);
+/// assert_eq!(sums, [1, 3, 6, 10, 15]);
+/// ```
+pub fn par_scan<T, F>(slice: &[T], op: F) -> Vec<T>
+where
+    T: Clone + Send + Sync,
+    F: Fn(&T, &T) -> T + Sync,
+{
+    let op = &op;
+
+    // First pass: scan each chunk independently.
+    let mut chunks = map_chunks(slice, |chunk| {
+        let mut scanned: Vec<T> = Vec::with_capacity(chunk.len());
+        for x in chunk {
+            let next = match scanned.last() {
+                None => x.clone(),
+                Some(prev) => op(prev, x),
+            };
+            scanned.push(next);
+        }
+        scanned
+    });
+
+    // Combine the totals of preceding chunks into a carry for each chunk.
+    let mut carries = Vec::with_capacity(chunks.len());
+    let mut carry: Option<T> = None;
+    for chunk in &chunks {
+        let total = chunk.last().unwrap();
+        let next = match &carry {
+            None => total.clone(),
+            Some(c) => op(c, total),
+        };
+        carries.push(carry.replace(next));
+    }
+
+    // Second pass: apply the carries to chunks.
+    Parallel::new()
+        .each(chunks.iter_mut().zip(carries), move |(chunk, carry)| {
+            if let Some(c) = carry {
+                for x in chunk.iter_mut() {
+                    *x = op(&c, x);
+                }
+            }
+        })
+        .run();
+
+    chunks.into_iter().flatten().collect()
+}
+
+/// Splits a mutable slice in two at `mid` and runs a closure on each half in parallel.
+///
+/// The left closure gets `&mut slice[..mid]` and runs on a spawned thread, while the right closure
+/// gets `&mut slice[mid..]` and runs on the current thread. Calls can be nested to express
+/// in-place divide-and-conquer algorithms.
+///
+/// If a closure panics, panicking will resume in the main thread after both closures complete.
+///
+/// # Panics
+///
+/// Panics if `mid > slice.len()`.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::par_join_mut;
+///
+/// fn quicksort(v: &mut [i32]) {
+///     if v.len() <= 1 {
+///         return;
+///     }
+///
+///     // Partition around the last element.
+///     let last = v.len() - 1;
+///     let mut mid = 0;
+///     for i in 0..last {
+///         if v[i] < v[last] {
+///             v.swap(i, mid);
+///             mid += 1;
+///         }
+///     }
+///     v.swap(mid, last);
+///
+///     par_join_mut(v, mid, quicksort, |right| quicksort(&mut right[1..]));
+/// }
+///
+/// let mut v = [5, 1, 4, 2, 8, 3];
+/// quicksort(&mut v);
+/// assert_eq!(v, [1, 2, 3, 4, 5, 8]);
+/// ```
+pub fn par_join_mut<T, A, B, FA, FB>(slice: &mut [T], mid: usize, left: FA, right: FB) -> (A, B)
+where
+    T: Send,
+    FA: FnOnce(&mut [T]) -> A + Send,
+    FB: FnOnce(&mut [T]) -> B,
+    A: Send,
+{
+    let (l, r) = slice.split_at_mut(mid);
+    let (mut a, b) = Parallel::new()
+        .add(move || left(l))
+        .finish(move || right(r));
+    (a.pop().unwrap(), b)
+}
+
+/// Runs two closures with different return types in parallel and returns both results.
+///
+/// The first closure runs on a spawned thread and the last one on the current thread, like with
+/// [`Parallel::finish()`]. This avoids wrapping results into a common enum when the closures
+/// produce unrelated values.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// let (len, words) = easy_parallel::join2(
+///     || "hello world".len(),
+///     || vec!["hello", "world"],
+/// );
+///
+/// assert_eq!(len, 11);
+/// assert_eq!(words, ["hello", "world"]);
+/// ```
+pub fn join2<A, B, FA, FB>(a: FA, b: FB) -> (A, B)
+where
+    FA: FnOnce() -> A + Send,
+    FB: FnOnce() -> B,
+    A: Send,
+{
+    let mut ra = None;
+    let sa = &mut ra;
+    let (_, b) = Parallel::new().add(move || *sa = Some(a())).finish(b);
+    (ra.unwrap(), b)
+}
+
+/// Runs three closures with different return types in parallel and returns all results.
+///
+/// See [`join2()`] for details.
+///
+/// # Examples
+///
+/// ```
+/// let (n, s, b) = easy_parallel::join3(|| 1 + 1, || "two".to_string(), || true);
+///
+/// assert_eq!((n, s.as_str(), b), (2, "two", true));
+/// ```
+pub fn join3<A, B, C, FA, FB, FC>(a: FA, b: FB, c: FC) -> (A, B, C)
+where
+    FA: FnOnce() -> A + Send,
+    FB: FnOnce() -> B + Send,
+    FC: FnOnce() -> C,
+    A: Send,
+    B: Send,
+{
+    let (mut ra, mut rb) = (None, None);
+    let (sa, sb) = (&mut ra, &mut rb);
+    let (_, c) = Parallel::new()
+        .add(move || *sa = Some(a()))
+        .add(move || *sb = Some(b()))
+        .finish(c);
+    (ra.unwrap(), rb.unwrap(), c)
+}
+
+/// Runs four closures with different return types in parallel and returns all results.
+///
+/// See [`join2()`] for details.
+///
+/// # Examples
+///
+/// ```
+/// let (a, b, c, d) = easy_parallel::join4(|| 1u8, || 2u16, || 3u32, || "four");
+///
+/// assert_eq!((a, b, c, d), (1, 2, 3, "four"));
+/// ```
+pub fn join4<A, B, C, D, FA, FB, FC, FD>(a: FA, b: FB, c: FC, d: FD) -> (A, B, C, D)
+where
+    FA: FnOnce() -> A + Send,
+    FB: FnOnce() -> B + Send,
+    FC: FnOnce() -> C + Send,
+    FD: FnOnce() -> D,
+    A: Send,
+    B: Send,
+    C: Send,
+{
+    let (mut ra, mut rb, mut rc) = (None, None, None);
+    let (sa, sb, sc) = (&mut ra, &mut rb, &mut rc);
+    let (_, d) = Parallel::new()
+        .add(move || *sa = Some(a()))
+        .add(move || *sb = Some(b()))
+        .add(move || *sc = Some(c()))
+        .finish(d);
+    (ra.unwrap(), rb.unwrap(), rc.unwrap(), d)
+}
+
+/// Parallel operations on slices.
+///
+/// This offers a tiny subset of iterator-style parallelism without a work-stealing runtime. Each
+/// operation splits the slice into contiguous chunks, one per
+/// [available][thread::available_parallelism] thread, and processes the chunks in parallel.
+///
+/// If a closure panics, panicking will resume in the main thread after all threads are joined.
+///
+/// # Examples
+///
+/// ```
+/// use easy_parallel::ParallelSlice;
+///
+/// let v = [1, 2, 3, 4];
+///
+/// assert_eq!(v.par_map(|x| x * x), [1, 4, 9, 16]);
+/// assert_eq!(v.par_fold(|| 0, |acc, x| acc + x, |a, b| a + b), 10);
+/// ```
+pub trait ParallelSlice<T: Sync> {
+    /// Maps each element with a closure and collects the results in order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelSlice;
+    ///
+    /// let words = ["a", "bb", "ccc"];
+    /// assert_eq!(words.par_map(|w| w.len()), [1, 2, 3]);
+    /// ```
+    fn par_map<U, F>(&self, f: F) -> Vec<U>
+    where
+        F: Fn(&T) -> U + Sync,
+        U: Send;
+
+    /// Like [`par_map()`][`ParallelSlice::par_map()`], but spawns threads with the given settings,
+    /// so that the chunks respect limits like [`ParallelConfig::max_threads()`] and
+    /// [`ParallelConfig::limit()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::{ParallelConfig, ParallelSlice};
+    ///
+    /// let config = ParallelConfig::new().limit(2);
+    ///
+    /// let words = ["a", "bb", "ccc"];
+    /// assert_eq!(words.par_map_with(&config, |w| w.len()), [1, 2, 3]);
+    /// ```
+    fn par_map_with<U, F>(&self, config: &ParallelConfig, f: F) -> Vec<U>
+    where
+        F: Fn(&T) -> U + Sync,
+        U: Send;
+
+    /// Calls a closure on each element.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelSlice;
+    /// use std::sync::atomic::{AtomicI32, Ordering};
+    ///
+    /// let sum = AtomicI32::new(0);
+    /// [1, 2, 3].par_for_each(|x| {
+    ///     sum.fetch_add(*x, Ordering::SeqCst);
+    /// });
+    ///
+    /// assert_eq!(sum.into_inner(), 6);
+    /// ```
+    fn par_for_each<F>(&self, f: F)
+    where
+        F: Fn(&T) + Sync;
+
+    /// Folds each chunk into an accumulator and combines the accumulators of all chunks.
+    ///
+    /// Each chunk starts from a fresh accumulator created by `identity`. Since the grouping of
+    /// elements into chunks depends on the number of threads, `combine` should be associative.
+    /// Returns `identity()` if the slice is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::ParallelSlice;
+    ///
+    /// let words = ["apple", "kiwi", "banana"];
+    ///
+    /// let longest = words.par_fold(
+    ///     || "",
+    ///     |a, b| if b.len() > a.len() { b } else { a },
+    ///     |a, b| if b.len() > a.len() { b } else { a },
+    /// );
+    ///
+    /// assert_eq!(longest, "banana");
+    /// ```
+    fn par_fold<A, ID, F, C>(&self, identity: ID, fold: F, combine: C) -> A
+    where
+        ID: Fn() -> A + Sync,
+        F: Fn(A, &T) -> A + Sync,
+        C: Fn(A, A) -> A,
+        A: Send;
+}
+
+impl<T: Sync> ParallelSlice<T> for [T] {
+    fn par_map<U, F>(&self, f: F) -> Vec<U>
+    where
+        F: Fn(&T) -> U + Sync,
+        U: Send,
+    {
+        map_chunks(self, |chunk| chunk.iter().map(&f).collect::<Vec<_>>())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn par_map_with<U, F>(&self, config: &ParallelConfig, f: F) -> Vec<U>
+    where
+        F: Fn(&T) -> U + Sync,
+        U: Send,
+    {
+        map_chunks_with(self, config, |chunk| {
+            chunk.iter().map(&f).collect::<Vec<_>>()
+        })
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn par_for_each<F>(&self, f: F)
+    where
+        F: Fn(&T) + Sync,
+    {
+        map_chunks(self, |chunk| chunk.iter().for_each(&f));
+    }
+
+    fn par_fold<A, ID, F, C>(&self, identity: ID, fold: F, combine: C) -> A
+    where
+        ID: Fn() -> A + Sync,
+        F: Fn(A, &T) -> A + Sync,
+        C: Fn(A, A) -> A,
+        A: Send,
+    {
+        map_chunks(self, |chunk| chunk.iter().fold(identity(), &fold))
+            .into_iter()
+            .reduce(combine)
+            .unwrap_or_else(identity)
+    }
+}
+
+/// Extension methods for running iterator pipelines in parallel.
+pub trait IteratorExt: Iterator + Sized {
+    /// Maps the items of an iterator with a closure on a bounded number of threads.
+    ///
+    /// Each of the `workers` threads repeatedly takes the next item from the iterator and maps
+    /// it, until the iterator is exhausted. The iterator is consumed lazily, so at most `workers`
+    /// items are being mapped at any time. Results are collected in the order of the items.
+    ///
+    /// If the iterator reports an upper bound on its length through
+    /// [`size_hint()`][Iterator::size_hint], no more workers than items are used.
+    ///
+    /// If a closure panics, panicking will resume in the main thread after all threads are joined.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use easy_parallel::IteratorExt;
+    ///
+    /// let lens = ["apple", "kiwi", "banana"]
+    ///     .into_iter()
+    ///     .filter(|w| w.contains('a'))
+    ///     .parallel_map(2, |w| w.len());
+    ///
+    /// assert_eq!(lens, [5, 6]);
+    /// ```
+    fn parallel_map<U, F>(self, workers: usize, f: F) -> Vec<U>
+    where
+        Self: Send,
+        Self::Item: Send,
+        F: Fn(Self::Item) -> U + Sync,
+        U: Send,
+    {
+        assert!(workers > 0, "cannot map items without workers");
+
+        // Don't spawn threads that would find no items to map.
+        let workers = match self.size_hint().1 {
+            Some(len) => workers.min(len.max(1)),
+            None => workers,
+        };
+
+        let source = Mutex::new(self.enumerate());
+        let (source, f) = (&source, &f);
+
+        let mut results = Parallel::new()
+            .each(0..workers, move |_| {
+                let mut mapped = Vec::new();
+                loop {
+                    // Release the lock before mapping the item.
+                    let next = source.lock().unwrap().next();
+                    match next {
+                        Some((i, item)) => mapped.push((i, f(item))),
+                        None => break mapped,
+                    }
+                }
+            })
+            .run()
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        results.sort_unstable_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, u)| u).collect()
+    }
+}
+
+impl<I: Iterator> IteratorExt for I {}
+
+/// Splits a slice into contiguous chunks, one per available thread, and maps them in parallel.
+fn map_chunks<T, R, F>(slice: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    F: Fn(&[T]) -> R + Sync,
+    R: Send,
+{
+    map_chunks_with(slice, &ParallelConfig::global(), f)
+}
+
+/// Like [`map_chunks()`], but spawns threads with the given settings.
+fn map_chunks_with<T, R, F>(slice: &[T], config: &ParallelConfig, f: F) -> Vec<R>
+where
+    T: Sync,
+    F: Fn(&[T]) -> R + Sync,
+    R: Send,
+{
+    let parts = available_threads().min(slice.len());
+    let f = &f;
+
+    Parallel::new()
+        .each(0..parts, move |i| {
+            f(&slice[chunk_range(slice.len(), parts, i)])
+        })
+        .with_config(config)
+        .run()
+}
+
+/// Returns the `i`-th of `parts` contiguous ranges that together cover `0..len`.
+///
+/// Range lengths differ by at most one.
+fn chunk_range(len: usize, parts: usize, i: usize) -> Range<usize> {
+    let start = i * (len / parts) + i.min(len % parts);
+    let size = len / parts + usize::from(i < len % parts);
+    start..start + size
+}
+
+/// Returns the number of threads worth spawning for splitting up work.
+fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
 }
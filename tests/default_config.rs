@@ -0,0 +1,35 @@
+use std::thread;
+
+use easy_parallel::{Parallel, ParallelConfig, ParallelMut};
+
+#[test]
+fn default_config() {
+    let name = || thread::current().name().map(String::from);
+
+    easy_parallel::set_default_config(ParallelConfig::new().name("default")).unwrap();
+    let err = easy_parallel::set_default_config(ParallelConfig::new().name("other"));
+    assert!(err.is_err());
+
+    let (names, ()) = Parallel::new().each(0..2, |_| name()).finish(|| ());
+    assert_eq!(
+        names,
+        [Some("default".to_string()), Some("default".to_string())]
+    );
+
+    let (names, ()) = Parallel::new()
+        .add(name)
+        .with_config(&ParallelConfig::new())
+        .finish(|| ());
+    assert_eq!(names, [None]);
+
+    let names = ParallelMut::new().add(name).add(|| None).run();
+    assert_eq!(names[0].as_deref(), Some("default"));
+
+    let (names, ()) = easy_parallel::eager(|batch| {
+        batch.add(name);
+    });
+    assert_eq!(names[0].as_deref(), Some("default"));
+
+    let names = Parallel::new().add_isolated(name).run();
+    assert_eq!(names[0].as_deref(), Some("default"));
+}
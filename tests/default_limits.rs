@@ -0,0 +1,68 @@
+use std::thread;
+
+use easy_parallel::{Parallel, ParallelConfig, ParallelMut};
+
+#[test]
+fn default_limits() {
+    easy_parallel::set_default_config(ParallelConfig::new().max_threads(0)).unwrap();
+    let main = thread::current().id();
+    let id = || thread::current().id();
+
+    assert!(Parallel::new()
+        .each(0..4, |_| id())
+        .run()
+        .iter()
+        .all(|&t| t == main));
+
+    let mut counts = [0; 3];
+    let [a, b, c] = &mut counts;
+    let runs = ParallelMut::new()
+        .add(|| {
+            *a += 1;
+            id()
+        })
+        .add(|| {
+            *b += 1;
+            id()
+        })
+        .add(|| {
+            *c += 1;
+            id()
+        })
+        .run_repeat(3);
+    assert!(runs.concat().iter().all(|&t| t == main));
+    assert_eq!(counts, [3, 3, 3]);
+
+    let mut batch = ParallelMut::new().each(0..3, |_| id());
+    assert!(batch.run().iter().all(|&t| t == main));
+    drop(batch);
+
+    let (ids, ()) = easy_parallel::eager(|batch| {
+        batch.add(id).add(id);
+    });
+    assert!(ids.iter().all(|&t| t == main));
+
+    assert_eq!(Parallel::new().add_isolated(id).run(), [main]);
+
+    // Closures that don't get a thread from `start()` run on the current thread.
+    assert_eq!(Parallel::new().each(0..3, |i| i).start().join(), [0, 1, 2]);
+
+    let one_thread = ParallelConfig::new().max_threads(1);
+    let running = Parallel::new()
+        .each(0..4, |i| i)
+        .with_config(&one_thread)
+        .start();
+    assert_eq!(running.join(), [0, 1, 2, 3]);
+
+    let no_nesting = ParallelConfig::new().max_depth(0);
+    let nested = Parallel::new()
+        .add(|| {
+            Parallel::new()
+                .each(0..3, |i| i)
+                .with_config(&no_nesting)
+                .start()
+                .join()
+        })
+        .run();
+    assert_eq!(nested, [[0, 1, 2]]);
+}
@@ -1,6 +1,10 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::panic;
 use std::sync::Mutex;
+use std::thread;
 
-use easy_parallel::Parallel;
+use easy_parallel::{IteratorExt, Parallel, ParallelMut, TaskInfo};
 
 #[test]
 fn smoke() {
@@ -36,3 +40,1293 @@ fn finish() {
     assert_eq!(squares, [100, 400, 900]);
     assert_eq!(len, 3);
 }
+
+#[test]
+fn split() {
+    let parts = Parallel::new().each(0..7, |i| i).split(3);
+    let results = parts.into_iter().map(|p| p.run()).collect::<Vec<_>>();
+    assert_eq!(results, [vec![0, 1, 2], vec![3, 4], vec![5, 6]]);
+
+    let parts = Parallel::new().add(|| 1).split(3);
+    let results = parts.into_iter().map(|p| p.run()).collect::<Vec<_>>();
+    assert_eq!(results, [vec![1], vec![], vec![]]);
+}
+
+#[test]
+#[should_panic(expected = "boom")]
+fn repeat_until_panic() {
+    easy_parallel::repeat_until(|| -> i32 { panic!("boom") }, |_| false);
+}
+
+#[test]
+fn collect_dedup() {
+    let set = Parallel::new()
+        .each(0..100, |i| i % 7)
+        .add(|| 3)
+        .collect::<BTreeSet<_>>();
+
+    assert_eq!(set.into_iter().collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn finish_with_progress() {
+    let (res, completed) = Parallel::new()
+        .each(0..10, |i| i)
+        .finish_with_progress(|progress| {
+            assert_eq!(progress.total(), 10);
+            progress.wait_for(usize::MAX);
+            progress.completed()
+        });
+
+    assert_eq!(res, (0..10).collect::<Vec<_>>());
+    assert_eq!(completed, 10);
+}
+
+#[test]
+#[should_panic(expected = "boom")]
+fn start_drop_panic() {
+    let running = Parallel::new().add(|| ()).add(|| panic!("boom")).start();
+    drop(running);
+}
+
+#[test]
+fn drop_unused() {
+    let p = Parallel::new().add(|| -> i32 { panic!("never runs") });
+    drop(p);
+
+    // Returning early with `?` drops the batch built so far.
+    fn build() -> Result<Vec<i32>, &'static str> {
+        let p = Parallel::new()
+            .add(|| -> i32 { panic!("never runs") })
+            .try_each(vec![Ok(1), Err("bad")], |i| i)?;
+        Ok(p.run())
+    }
+    assert_eq!(build(), Err("bad"));
+}
+
+#[test]
+fn parallel_mut_after_panic() {
+    let mut calls = 0;
+    let mut batch = ParallelMut::new().add(|| 1).add(|| {
+        calls += 1;
+        if calls == 1 {
+            panic!("first run");
+        }
+        2
+    });
+
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| batch.run()));
+    assert!(res.is_err());
+    assert_eq!(batch.run(), [1, 2]);
+}
+
+#[test]
+fn run_repeat_reuses_threads() {
+    let mut batch = ParallelMut::new()
+        .each(0..3, |_| thread::current().id())
+        .add(|| thread::current().id());
+
+    let runs = batch.run_repeat(5);
+    assert_eq!(runs.len(), 5);
+    for run in &runs {
+        assert_eq!(run, &runs[0]);
+    }
+    assert_eq!(runs[0][3], thread::current().id());
+}
+
+#[test]
+fn bench_per_closure() {
+    let ms = std::time::Duration::from_millis;
+    let (mut fast, mut slow) = (0, 0);
+    let stats = ParallelMut::new()
+        .add(|| fast += 1)
+        .add(|| {
+            slow += 1;
+            thread::sleep(ms(5));
+        })
+        .bench(4);
+
+    assert_eq!((fast, slow), (4, 4));
+    assert_eq!(stats.len(), 2);
+    assert!(stats[1].min() >= ms(5));
+    assert!(stats[0].max() < stats[1].min());
+    for s in &stats {
+        assert!(s.min() <= s.mean() && s.mean() <= s.p95() && s.p95() <= s.max());
+    }
+
+    let stats = ParallelMut::new().add(|| ()).bench(0);
+    assert_eq!(stats, [easy_parallel::BenchStats::default()]);
+}
+
+#[test]
+#[should_panic(expected = "third run")]
+fn run_repeat_panic() {
+    let mut runs = 0;
+    ParallelMut::new()
+        .add(|| {
+            runs += 1;
+            if runs == 3 {
+                panic!("third run");
+            }
+        })
+        .add(|| ())
+        .run_repeat(5);
+}
+
+#[test]
+fn current_task_index() {
+    let (res, main) = Parallel::new()
+        .each(0..3, |_| easy_parallel::current_task_index())
+        .finish(easy_parallel::current_task_index);
+    assert_eq!(res, [Some(0), Some(1), Some(2)]);
+    assert_eq!(main, None);
+
+    let mut batch = ParallelMut::new()
+        .each(0..3, |_| easy_parallel::current_task_index().unwrap())
+        .add(|| Parallel::new().add(easy_parallel::current_task_index).run()[0].unwrap());
+    assert_eq!(batch.run_repeat(2), [[0, 1, 2, 0], [0, 1, 2, 0]]);
+    assert_eq!(easy_parallel::current_task_index(), None);
+}
+
+#[test]
+fn parallel_map_bounded() {
+    let res = (0..1000).parallel_map(3, |i| (i * 2, thread::current().id()));
+
+    assert_eq!(
+        res.iter().map(|(x, _)| *x).collect::<Vec<_>>(),
+        (0..1000).map(|i| i * 2).collect::<Vec<_>>()
+    );
+
+    let threads = res.iter().map(|(_, id)| *id).collect::<HashSet<_>>();
+    assert!(threads.len() <= 3);
+
+    // No more workers than items are used.
+    let totals = (0..2).parallel_map(8, |_| easy_parallel::TaskInfo::current().unwrap().total());
+    assert_eq!(totals, [2, 2]);
+}
+
+#[test]
+fn par_scan() {
+    let v = (1..=1000).collect::<Vec<u64>>();
+    let expected = v
+        .iter()
+        .scan(0, |acc, x| {
+            *acc += x;
+            Some(*acc)
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(easy_parallel::par_scan(&v, |a, b| a + b), expected);
+    assert_eq!(easy_parallel::par_scan(&[] as &[u64], |a, b| a + b), []);
+
+    let strings = ["a", "b", "c"].map(String::from);
+    assert_eq!(
+        easy_parallel::par_scan(&strings, |a, b| a.clone() + b),
+        ["a", "ab", "abc"]
+    );
+}
+
+#[test]
+fn map_reduce_order() {
+    let concat = easy_parallel::map_reduce(0..100, |i| i.to_string(), |a, b| a + &b);
+    assert_eq!(concat, Some((0..100).map(|i| i.to_string()).collect()));
+
+    let empty = easy_parallel::map_reduce(Vec::<i32>::new(), |i| i, |a, b| a + b);
+    assert_eq!(empty, None);
+}
+
+#[test]
+fn finish_ordered_early_exit() {
+    let first_two = Parallel::new()
+        .each(0..10, |i| i * 2)
+        .finish_ordered(|results| results.take(2).collect::<Vec<_>>());
+
+    assert_eq!(first_two, [0, 2]);
+}
+
+#[test]
+#[should_panic(expected = "boom")]
+fn finish_ordered_panic() {
+    Parallel::new()
+        .add(|| 1)
+        .add(|| panic!("boom"))
+        .add(|| 3)
+        .finish_ordered(|results| assert_eq!(results.collect::<Vec<_>>(), [1]));
+}
+
+#[test]
+fn eager_starts_before_build_returns() {
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let (v, received) = easy_parallel::eager(|batch| {
+        batch.add(move || tx.send(1).unwrap());
+        // Would block forever if the closure did not start until the batch is built.
+        rx.recv().unwrap()
+    });
+
+    assert_eq!(v, [()]);
+    assert_eq!(received, 1);
+}
+
+#[test]
+fn rate_limit_spaces_starts() {
+    let origin = std::time::Instant::now();
+
+    let mut starts = Parallel::new()
+        .each(0..4, |_| origin.elapsed())
+        .rate_limit(20.0)
+        .run();
+    starts.sort();
+
+    for (i, start) in starts.into_iter().enumerate() {
+        assert!(start >= std::time::Duration::from_millis(50) * i as u32);
+    }
+
+    // Extremely low rates are clamped, and the first closure starts right away.
+    let res = Parallel::new().add(|| 1).rate_limit(1e-300).run();
+    assert_eq!(res, [1]);
+}
+
+#[test]
+fn run_step_resumes() {
+    let mut p = Parallel::new().each(0..10, |i| {
+        thread::sleep(std::time::Duration::from_millis(10));
+        i
+    });
+
+    // A zero budget still makes progress, one round at a time.
+    let mut res = p.run_step(std::time::Duration::ZERO);
+    assert!(!res.is_empty());
+
+    while !p.is_empty() {
+        res.extend(p.run_step(std::time::Duration::ZERO));
+    }
+    assert_eq!(res, (0..10).collect::<Vec<_>>());
+
+    // An unbounded budget runs everything.
+    let mut p = Parallel::new().each(0..10, |i| i);
+    assert_eq!(
+        p.run_step(std::time::Duration::MAX),
+        (0..10).collect::<Vec<_>>()
+    );
+    assert!(p.is_empty());
+
+    // Indices stay the same as in a single batch across steps and rounds.
+    let seen = Mutex::new(Vec::new());
+    let mut p = Parallel::new()
+        .each(0..200, |_| easy_parallel::current_task_index())
+        .add_with_info(|info| Some(info.index() * 1000 + info.total()))
+        .inspect(|index, _| seen.lock().unwrap().push(index));
+    let mut res = Vec::new();
+    while !p.is_empty() {
+        res.extend(p.run_step(std::time::Duration::ZERO));
+    }
+    drop(p);
+    let mut expected = (0..200).map(Some).collect::<Vec<_>>();
+    expected.push(Some(200 * 1000 + 201));
+    assert_eq!(res, expected);
+    let mut seen = seen.into_inner().unwrap();
+    seen.sort();
+    assert_eq!(seen, (0..201).collect::<Vec<_>>());
+}
+
+#[test]
+fn effective_threads() {
+    let p = Parallel::<()>::new();
+    assert_eq!(p.effective_threads(), 0);
+    assert!(!p.will_parallelize());
+
+    let p = Parallel::new().add(|| ());
+    assert_eq!(p.effective_threads(), 1);
+    assert!(!p.will_parallelize());
+
+    let p = p.add(|| ()).add(|| ());
+    assert_eq!(p.effective_threads(), 3);
+    assert!(p.will_parallelize());
+    p.run();
+}
+
+#[test]
+fn each_within_budget() {
+    let (done, rest) = easy_parallel::each_within(0..10, std::time::Duration::from_secs(60), |i| i);
+    assert_eq!(done, (0..10).collect::<Vec<_>>());
+    assert_eq!(rest.count(), 0);
+
+    let (done, rest) = easy_parallel::each_within(0..10, std::time::Duration::ZERO, |i| i);
+    assert!(done.is_empty());
+    assert_eq!(rest.collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+
+    let (done, rest) = easy_parallel::each_within(0..10, std::time::Duration::MAX, |i| i);
+    assert_eq!(done, (0..10).collect::<Vec<_>>());
+    assert_eq!(rest.count(), 0);
+}
+
+#[test]
+fn each_resumable_skips_completed() {
+    let journal = Mutex::new(Vec::new());
+
+    let res = Parallel::new()
+        .each_resumable(
+            (0..6).map(|i| (i, i)),
+            |&k| k % 2 == 0,
+            |k| journal.lock().unwrap().push(k),
+            |i| i * 10,
+        )
+        .run();
+
+    assert_eq!(res, [10, 30, 50]);
+    let mut journal = journal.into_inner().unwrap();
+    journal.sort();
+    assert_eq!(journal, [1, 3, 5]);
+}
+
+#[test]
+fn task_location() {
+    fn line() -> u32 {
+        let info = easy_parallel::TaskInfo::current().unwrap();
+        info.location().unwrap().line()
+    }
+
+    let first = line!() + 2;
+    let lines = Parallel::new()
+        .add(line)
+        .each(0..2, |_| line())
+        .add_with_info(|info| info.location().unwrap().line())
+        .run();
+
+    assert_eq!(lines, [first, first + 1, first + 1, first + 2]);
+    assert_eq!(easy_parallel::TaskInfo::current(), None);
+
+    // Panics keep their payload, and the panic hook can look up the location.
+    let hooked = std::sync::Arc::new(Mutex::new(None));
+    let prev = panic::take_hook();
+    panic::set_hook(Box::new({
+        let hooked = hooked.clone();
+        move |panic| {
+            // Other tests may panic while the hook is set.
+            if panic.payload().downcast_ref::<&str>() == Some(&"located boom") {
+                let info = easy_parallel::TaskInfo::current();
+                *hooked.lock().unwrap() = info.and_then(|info| info.location()).map(|l| l.line());
+            }
+        }
+    }));
+    let line = line!() + 3;
+    let err = panic::catch_unwind(|| {
+        Parallel::new()
+            .add(|| -> i32 { panic!("located boom") })
+            .add(|| 1)
+            .run()
+    })
+    .unwrap_err();
+    panic::set_hook(prev);
+    assert_eq!(err.downcast_ref::<&str>(), Some(&"located boom"));
+    assert_eq!(*hooked.lock().unwrap(), Some(line));
+
+    let res = Parallel::new()
+        .add(|| -> i32 { panic!("boom") })
+        .run_catch();
+    let err = res.into_iter().next().unwrap().unwrap_err();
+    assert_eq!(err.downcast_ref::<&str>(), Some(&"boom"));
+}
+
+#[test]
+fn config_applies_to_many_builders() {
+    let config = easy_parallel::ParallelConfig::new()
+        .name("worker")
+        .stack_size(4 * 1024 * 1024);
+
+    for _ in 0..2 {
+        let (names, ()) = Parallel::new()
+            .each(0..3, |_| thread::current().name().map(String::from))
+            .with_config(&config)
+            .finish(|| ());
+        assert!(names.iter().all(|n| n.as_deref() == Some("worker")));
+    }
+
+    let names = Parallel::new()
+        .add(|| thread::current().name().map(String::from))
+        .with_config(&config)
+        .start()
+        .join();
+    assert_eq!(names, [Some("worker".to_string())]);
+}
+
+#[test]
+fn oversubscription_hook() {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let config = easy_parallel::ParallelConfig::new().on_oversubscribe({
+        let calls = calls.clone();
+        move |running, available| {
+            assert!(running > available);
+            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    });
+
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    Parallel::new()
+        .each(0..2 * cpus + 1, |_| ())
+        .with_config(&config)
+        .run();
+
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn spawn_error_degrades() {
+    let errors = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let config = easy_parallel::ParallelConfig::new()
+        // No system can allocate a stack this large.
+        .stack_size(1 << 60)
+        .on_spawn_error({
+            let errors = errors.clone();
+            move |_| {
+                errors.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        });
+
+    // The batch completes on the current thread.
+    let res = Parallel::new().each(0..4, |i| i).with_config(&config).run();
+    assert_eq!(res, [0, 1, 2, 3]);
+    assert_eq!(errors.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    let res = Parallel::new()
+        .each(0..4, |i| i)
+        .with_config(&config)
+        .start()
+        .join();
+    assert_eq!(res, [0, 1, 2, 3]);
+    assert_eq!(errors.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
+
+#[test]
+fn run_grouped() {
+    let res = Parallel::new()
+        .add_in_group("a", || 1)
+        .each_in_group("b", 2..5, |i| i)
+        .add_in_group("a", || 5)
+        .run_grouped();
+
+    assert_eq!(res.len(), 2);
+    assert_eq!(res["a"], [1, 5]);
+    assert_eq!(res["b"], [2, 3, 4]);
+
+    let empty = Parallel::<(&str, i32)>::new().run_grouped();
+    assert!(empty.is_empty());
+}
+
+#[test]
+fn depth_and_thread_limits() {
+    let config = easy_parallel::ParallelConfig::new().max_depth(0);
+    let res = Parallel::new()
+        .each(0..3, |_| {
+            let outer = thread::current().id();
+            let inner = Parallel::new()
+                .each(0..3, |_| thread::current().id())
+                .with_config(&config)
+                .run();
+            inner.into_iter().all(|id| id == outer)
+        })
+        .with_config(&config)
+        .run();
+    assert_eq!(res, [true, true, true]);
+
+    // Every closure of a `ParallelMut` counts as nested, including the one on the current thread.
+    let mut batch = ParallelMut::new().each(0..3, |_| {
+        Parallel::new()
+            .each(0..4, |i| i)
+            .with_config(&config)
+            .effective_threads()
+    });
+    assert_eq!(batch.run(), [1, 1, 1]);
+    assert_eq!(batch.run_repeat(2), [[1, 1, 1], [1, 1, 1]]);
+
+    let config = easy_parallel::ParallelConfig::new().max_threads(0);
+    let ids = Parallel::new()
+        .each(0..10, |_| thread::current().id())
+        .with_config(&config)
+        .run();
+    assert!(ids.iter().all(|&id| id == thread::current().id()));
+}
+
+#[test]
+fn effective_threads_respects_limits() {
+    let config = easy_parallel::ParallelConfig::new().max_threads(0);
+    let p = Parallel::new().each(0..4, |i| i).with_config(&config);
+    assert_eq!(p.effective_threads(), 1);
+    assert!(!p.will_parallelize());
+    p.run();
+
+    let config = easy_parallel::ParallelConfig::new().max_depth(0);
+    let nested = Parallel::new()
+        .add(|| {
+            let p = Parallel::new().each(0..4, |i| i).with_config(&config);
+            let n = p.effective_threads();
+            p.run();
+            n
+        })
+        .run();
+    assert_eq!(nested, [1]);
+
+    let p = Parallel::new().each(0..8, |i| i).limit(2);
+    assert!(p.effective_threads() <= 3);
+    p.run();
+}
+
+#[test]
+fn send_results_to_channel() {
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    Parallel::new()
+        .each(0..5, |i| i * i)
+        .send_results_to(sender);
+
+    let mut res = receiver.iter().collect::<Vec<_>>();
+    res.sort();
+    assert_eq!(res, [(0, 0), (1, 1), (2, 4), (3, 9), (4, 16)]);
+
+    // Results are discarded if nobody is listening.
+    let (sender, receiver) = std::sync::mpsc::channel();
+    drop(receiver);
+    Parallel::new().add(|| 1).send_results_to(sender);
+}
+
+#[test]
+fn run_merge_sorted_is_stable() {
+    // Compares only by key, so that the order of equal items is observable.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Item(u32, char);
+
+    impl PartialOrd for Item {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Item {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    let merged = Parallel::new()
+        .add(|| vec![Item(1, 'a'), Item(3, 'a')])
+        .add(|| vec![Item(1, 'b'), Item(2, 'b')])
+        .add(Vec::new)
+        .add(|| vec![Item(1, 'c')])
+        .run_merge_sorted();
+
+    let expected = [(1, 'a'), (1, 'b'), (1, 'c'), (2, 'b'), (3, 'a')];
+    assert_eq!(merged, expected.map(|(k, c)| Item(k, c)));
+
+    assert!(Parallel::<Vec<u8>>::new().run_merge_sorted().is_empty());
+}
+
+#[test]
+fn each_with_affinity_same_thread() {
+    let items = (0..20).map(|i| (i % 3, i));
+    let res = easy_parallel::each_with_affinity(items, |i| (i, thread::current().id()));
+
+    assert_eq!(
+        res.iter().map(|r| r.0).collect::<Vec<_>>(),
+        (0..20).collect::<Vec<_>>()
+    );
+    for (i, (_, id)) in res.iter().enumerate() {
+        assert_eq!(*id, res[i % 3].1);
+    }
+
+    // Many more keys than cores don't get a thread each.
+    let items = (0..1000).map(|i| (i % 500, i));
+    let res = easy_parallel::each_with_affinity(items, |i| (i, thread::current().id()));
+    let threads = res.iter().map(|r| r.1).collect::<HashSet<_>>();
+    assert!(threads.len() <= thread::available_parallelism().unwrap().get());
+    for (i, (_, id)) in res.iter().enumerate() {
+        assert_eq!(*id, res[i % 500].1);
+    }
+}
+
+#[test]
+fn par_map_with_config() {
+    use easy_parallel::ParallelSlice;
+
+    let main = thread::current().id();
+    let config = easy_parallel::ParallelConfig::new().max_threads(0);
+    let items = (0..100).collect::<Vec<_>>();
+    let res = items.par_map_with(&config, |&i| (i * 2, thread::current().id()));
+
+    assert_eq!(
+        res.iter().map(|r| r.0).collect::<Vec<_>>(),
+        (0..100).map(|i| i * 2).collect::<Vec<_>>()
+    );
+    assert!(res.iter().all(|r| r.1 == main));
+}
+
+#[test]
+fn add_isolated_fresh_thread() {
+    thread_local! {
+        static USED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+
+    let main = thread::current().id();
+    let res = Parallel::new()
+        .add(|| USED.with(|u| u.replace(true)))
+        .add_isolated(|| {
+            assert_ne!(thread::current().id(), main);
+            assert_eq!(easy_parallel::current_task_index(), Some(1));
+            USED.with(|u| u.replace(true))
+        })
+        .run();
+    assert_eq!(res, [false, false]);
+
+    let res = panic::catch_unwind(|| Parallel::new().add_isolated(|| panic!("boom")).run());
+    assert!(res.is_err());
+}
+
+#[test]
+fn run_until_panic() {
+    let mut runs = 0;
+    let res = ParallelMut::new()
+        .add(|| runs += 1)
+        .add(|| ())
+        .run_until_panic(5);
+    assert!(res.is_none());
+    assert_eq!(runs, 5);
+}
+
+#[test]
+fn inspect_results() {
+    let seen = Mutex::new(Vec::new());
+
+    let res = Parallel::new()
+        .each(0..4, |i| i * 2)
+        .inspect(|index, &t| seen.lock().unwrap().push((index, t)))
+        .run();
+    assert_eq!(res, [0, 2, 4, 6]);
+    assert_eq!(*seen.lock().unwrap(), [(0, 0), (1, 2), (2, 4), (3, 6)]);
+
+    seen.lock().unwrap().clear();
+    let (res, ()) = Parallel::new()
+        .each(0..2, |i| i)
+        .inspect(|index, &t| seen.lock().unwrap().push((index, t)))
+        .finish_with_progress(|_| ());
+    assert_eq!(res, [0, 1]);
+    assert_eq!(*seen.lock().unwrap(), [(0, 0), (1, 1)]);
+
+    seen.lock().unwrap().clear();
+    let res = Parallel::new()
+        .each(0..3, |i| i)
+        .inspect(|index, &t| seen.lock().unwrap().push((index, t)))
+        .finish_ordered(|results| results.collect::<Vec<_>>());
+    assert_eq!(res, [0, 1, 2]);
+    assert_eq!(*seen.lock().unwrap(), [(0, 0), (1, 1), (2, 2)]);
+
+    seen.lock().unwrap().clear();
+    let res = Parallel::new()
+        .each(0..3, |i| i)
+        .inspect(|index, &t| seen.lock().unwrap().push((index, t)))
+        .finish_streaming(|results| results.collect::<BTreeSet<_>>());
+    assert_eq!(res, BTreeSet::from([0, 1, 2]));
+    let mut inspected = seen.lock().unwrap().clone();
+    inspected.sort();
+    assert_eq!(inspected, [(0, 0), (1, 1), (2, 2)]);
+
+    seen.lock().unwrap().clear();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    Parallel::new()
+        .each(0..3, |i| i)
+        .inspect(|index, &t| seen.lock().unwrap().push((index, t)))
+        .send_results_to(sender);
+    let mut sent = receiver.iter().collect::<Vec<_>>();
+    sent.sort();
+    assert_eq!(sent, [(0, 0), (1, 1), (2, 2)]);
+    let mut inspected = seen.lock().unwrap().clone();
+    inspected.sort();
+    assert_eq!(inspected, sent);
+
+    let seen = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let running = Parallel::new()
+        .each(0..3, |i| i)
+        .inspect({
+            let seen = seen.clone();
+            move |index, &t| seen.lock().unwrap().push((index, t))
+        })
+        .start();
+    assert_eq!(running.join(), [0, 1, 2]);
+    assert_eq!(*seen.lock().unwrap(), [(0, 0), (1, 1), (2, 2)]);
+}
+
+#[test]
+fn each_mut_in_place() {
+    let mut v = vec![1, 2, 3, 4];
+    Parallel::new().each_mut(v.iter_mut(), |x| *x *= 10).run();
+    assert_eq!(v, [10, 20, 30, 40]);
+}
+
+#[test]
+fn run_catching_converts_panics() {
+    let res = Parallel::new()
+        .each(0..4, |i| if i == 2 { panic!("boom") } else { Ok(i) })
+        .run_catching_with(|payload| *payload.downcast::<&str>().unwrap());
+    assert_eq!(res, [Ok(0), Ok(1), Err("boom"), Ok(3)]);
+}
+
+#[test]
+fn try_each_aborts_on_error() {
+    let seen = Mutex::new(0);
+    let res = Parallel::new()
+        .add(|| *seen.lock().unwrap() += 1)
+        .try_each(vec![Ok(1), Err("bad"), Ok(3)], |_: i32| {
+            *seen.lock().unwrap() += 1
+        });
+    assert_eq!(res.err(), Some("bad"));
+    assert_eq!(*seen.lock().unwrap(), 0);
+
+    let v = Parallel::new()
+        .try_each(vec![Ok::<_, ()>(1), Ok(2)], |i| i * 10)
+        .unwrap()
+        .run();
+    assert_eq!(v, [10, 20]);
+}
+
+#[test]
+fn finish_cancellable_skips_queued() {
+    let token = easy_parallel::CancelToken::new();
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let (res, ()) = Parallel::new()
+        .each(0..50, |i| {
+            sender.send(i).unwrap();
+            thread::sleep(std::time::Duration::from_millis(1));
+            i
+        })
+        .limit(1)
+        .finish_cancellable(&token, || {
+            // The main closure runs while the queue is worked off.
+            receiver.recv().unwrap();
+            token.cancel();
+        });
+
+    // The worker takes closures in order, so the skipped ones come last.
+    let ran = res.iter().take_while(|r| r.is_some()).count();
+    assert!((1..50).contains(&ran));
+    assert!(res[ran..].iter().all(Option::is_none));
+    assert_eq!(res[..ran], (0..ran).map(Some).collect::<Vec<_>>()[..]);
+}
+
+#[test]
+fn run_until_stops_early() {
+    let ran = std::sync::atomic::AtomicUsize::new(0);
+    let res = Parallel::new()
+        .each(0..50, |i| {
+            ran.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(1));
+            i
+        })
+        .limit(1)
+        .run_until(|res| res.len() >= 3);
+    assert_eq!(res, [0, 1, 2]);
+    assert!(ran.into_inner() < 50);
+
+    // Without a satisfied predicate, all results are returned.
+    let mut res = Parallel::new().each(0..5, |i| i).run_until(|_| false);
+    res.sort();
+    assert_eq!(res, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn take_first_completions() {
+    let res = Parallel::new().each(0..20, |i| i).limit(1).take_first(4);
+    assert_eq!(res, [0, 1, 2, 3]);
+
+    assert!(Parallel::new().each(0..3, |i| i).take_first(0).is_empty());
+    assert_eq!(Parallel::new().each(0..3, |i| i).take_first(5).len(), 3);
+}
+
+#[test]
+fn task_local_values() {
+    thread_local! {
+        static SEED: RefCell<usize> = const { RefCell::new(0) };
+    }
+    let seed = || SEED.with(|s| *s.borrow());
+
+    // Each closure starts with a fresh value, even when closures share a thread.
+    let res = Parallel::new()
+        .each(0..10, |_| {
+            SEED.with(|s| *s.borrow_mut() += 100);
+            seed()
+        })
+        .task_local(&SEED, || {
+            let info = TaskInfo::current().unwrap();
+            assert_eq!(info.location(), None);
+            info.index()
+        })
+        .limit(2)
+        .run();
+    assert_eq!(res, (0..10).map(|i| i + 100).collect::<Vec<_>>());
+    assert_eq!(seed(), 0);
+
+    // The previous value is restored after a panic.
+    let res = panic::catch_unwind(|| {
+        Parallel::new()
+            .add(|| panic!("boom"))
+            .task_local(&SEED, || 5)
+            .run()
+    });
+    assert!(res.is_err());
+    assert_eq!(seed(), 0);
+}
+
+#[test]
+fn pausable_holds_back_closures() {
+    let token = easy_parallel::PauseToken::new();
+    token.pause();
+
+    let started = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let running = Parallel::new()
+        .each(0..4, {
+            let started = started.clone();
+            move |i| {
+                started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                i
+            }
+        })
+        .pausable(&token)
+        .start();
+
+    thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+    token.resume();
+    assert_eq!(running.join(), [0, 1, 2, 3]);
+    assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 4);
+}
+
+#[test]
+fn summary_queue_latency() {
+    let ms = std::time::Duration::from_millis;
+
+    // One spawned thread and the current thread share six closures.
+    let (_, summary) = Parallel::new()
+        .each(0..6, |_| thread::sleep(ms(20)))
+        .limit(1)
+        .run_with_summary();
+    assert!(summary.queued_max() >= ms(40));
+    assert!(summary.queued_p50() <= summary.queued_max());
+
+    // Waiting for the rate limit doesn't count.
+    let (_, summary) = Parallel::new()
+        .each(0..3, |i| i)
+        .rate_limit(10.0)
+        .run_with_summary();
+    assert!(summary.queued_max() < ms(100));
+}
+
+#[test]
+fn fair_dispatch_interleaves_sources() {
+    // Without spawned threads, the current thread takes closures from the queue one by one.
+    let config = easy_parallel::ParallelConfig::new()
+        .max_threads(0)
+        .limit(1)
+        .fair();
+    let started = Mutex::new(Vec::new());
+    let res = Parallel::new()
+        .each(0..3, |i| started.lock().unwrap().push(('a', i)))
+        .add(|| started.lock().unwrap().push(('b', 0)))
+        .each(0..2, |i| started.lock().unwrap().push(('c', i)))
+        .add(|| started.lock().unwrap().push(('d', 0)))
+        .with_config(&config)
+        .run();
+    assert_eq!(res.len(), 7);
+
+    // The last closure runs on the current thread after the queue.
+    let started = started.into_inner().unwrap();
+    assert_eq!(
+        started,
+        [
+            ('a', 0),
+            ('b', 0),
+            ('c', 0),
+            ('a', 1),
+            ('c', 1),
+            ('a', 2),
+            ('d', 0)
+        ]
+    );
+
+    // Results keep the order in which closures were added.
+    let res = Parallel::new()
+        .each(0..3, |i| i)
+        .each(10..13, |i| i)
+        .with_config(&config)
+        .run();
+    assert_eq!(res, [0, 1, 2, 10, 11, 12]);
+}
+
+#[test]
+fn run_cancellable_from_inside() {
+    // Without spawned threads, the current thread takes closures from the queue one by one.
+    let config = easy_parallel::ParallelConfig::new().max_threads(0).limit(1);
+    let token = easy_parallel::CancelToken::new();
+    let res = Parallel::new()
+        .each(0..5, |i| {
+            if i == 1 {
+                token.cancel();
+            }
+            i
+        })
+        .with_config(&config)
+        .run_cancellable(&token);
+    assert_eq!(res, [Some(0), Some(1), None, None, None]);
+
+    let token = easy_parallel::CancelToken::new();
+    let res = Parallel::new().each(0..3, |i| i).run_cancellable(&token);
+    assert_eq!(res, [Some(0), Some(1), Some(2)]);
+}
+
+#[test]
+fn run_outcomes_per_failure_mode() {
+    use easy_parallel::{CancelToken, Outcome};
+
+    // Without spawned threads, the current thread takes closures from the queue one by one.
+    let config = easy_parallel::ParallelConfig::new().max_threads(0).limit(1);
+    let token = CancelToken::new();
+    let res = Parallel::new()
+        .each(0..4, |i| match i {
+            0 => 0,
+            1 => panic!("bad input"),
+            _ => {
+                token.cancel();
+                i
+            }
+        })
+        .with_config(&config)
+        .run_outcomes(&token, std::time::Duration::MAX);
+    assert!(res[0].is_completed());
+    assert!(matches!(res[1], Outcome::Panicked(_)));
+    assert!(matches!(res[2], Outcome::Completed(2)));
+    assert!(matches!(res[3], Outcome::Cancelled));
+
+    let res = Parallel::new()
+        .each(0..3, |i| i)
+        .run_outcomes(&CancelToken::new(), std::time::Duration::ZERO);
+    assert!(res.iter().all(|o| matches!(o, Outcome::TimedOut)));
+
+    let res = Parallel::new()
+        .each(0..3, |i| i)
+        .run_outcomes(&CancelToken::new(), std::time::Duration::MAX);
+    let res = res.into_iter().map(Outcome::completed).collect::<Vec<_>>();
+    assert_eq!(res, [Some(0), Some(1), Some(2)]);
+}
+
+#[test]
+fn fold_with_accumulators() {
+    let sum = easy_parallel::fold_with(1..=100u64, || 0, |acc, i| acc + i, |a, b| a + b);
+    assert_eq!(sum, 5050);
+
+    let empty = easy_parallel::fold_with(Vec::<u64>::new(), || 7, |acc, i| acc + i, |a, b| a + b);
+    assert_eq!(empty, 7);
+}
+
+#[test]
+fn each_2d_grid() {
+    let grid = easy_parallel::each_2d(2..7, 10..13, |y, x| (y, x));
+    assert_eq!(grid.len(), 5);
+    for (i, row) in grid.iter().enumerate() {
+        assert_eq!(*row, [(i + 2, 10), (i + 2, 11), (i + 2, 12)]);
+    }
+
+    assert!(easy_parallel::each_2d(0..0, 0..3, |y, x| y + x).is_empty());
+}
+
+#[test]
+fn spawn_hook() {
+    let threads = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let config = easy_parallel::ParallelConfig::new().on_spawn({
+        let threads = threads.clone();
+        move |t| threads.lock().unwrap().push(t.id())
+    });
+
+    let ids = Parallel::new()
+        .each(0..4, |_| thread::current().id())
+        .with_config(&config)
+        .run();
+
+    let main = thread::current().id();
+    let spawned = threads.lock().unwrap().clone();
+    let expected = ids
+        .into_iter()
+        .filter(|&id| id != main)
+        .collect::<HashSet<_>>();
+    assert_eq!(spawned.into_iter().collect::<HashSet<_>>(), expected);
+}
+
+#[test]
+fn flat_results() {
+    let v = Parallel::new()
+        .add_flat(|| 0..0)
+        .each(1..4, |i| vec![i; i])
+        .add_flat(|| Some(9))
+        .run_flat();
+    assert_eq!(v, [1, 2, 2, 3, 3, 3, 9]);
+}
+
+#[test]
+fn each_with_resource_bounds_concurrency() {
+    let running = std::sync::atomic::AtomicUsize::new(0);
+    let peak = std::sync::atomic::AtomicUsize::new(0);
+    let mut pool = vec![Vec::new(); 2];
+
+    let res = easy_parallel::each_with_resource(&mut pool, 0..20, |log, i| {
+        use std::sync::atomic::Ordering::SeqCst;
+        let now = running.fetch_add(1, SeqCst) + 1;
+        peak.fetch_max(now, SeqCst);
+        thread::sleep(std::time::Duration::from_millis(1));
+        running.fetch_sub(1, SeqCst);
+        log.push(i);
+        i * 2
+    });
+
+    assert_eq!(res, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+    assert!(peak.into_inner() <= 2);
+    let mut handled = pool.concat();
+    handled.sort();
+    assert_eq!(handled, (0..20).collect::<Vec<_>>());
+}
+
+#[test]
+fn limit_multiplexes_closures() {
+    let ids = Parallel::new()
+        .each(0..500, |i| {
+            (
+                i,
+                easy_parallel::current_task_index(),
+                thread::current().id(),
+            )
+        })
+        .limit(3)
+        .run();
+
+    for (i, (item, index, _)) in ids.iter().enumerate() {
+        assert_eq!((*item, *index), (i, Some(i)));
+    }
+    let threads = ids.iter().map(|&(_, _, id)| id).collect::<HashSet<_>>();
+    assert!(threads.len() <= 4);
+
+    let p = Parallel::new().each(0..10, |i| i).limit(2);
+    assert!(p.effective_threads() <= 3);
+    p.run();
+
+    assert_eq!(
+        Parallel::new().each(0..5, |i| i).limit(0).run(),
+        [0, 1, 2, 3, 4]
+    );
+}
+
+#[test]
+fn limit_runs_all_closures_on_panic() {
+    let count = Mutex::new(0);
+    let res = panic::catch_unwind(|| {
+        Parallel::new()
+            .each(0..50, |i| {
+                *count.lock().unwrap() += 1;
+                if i % 10 == 3 {
+                    panic!("boom");
+                }
+            })
+            .limit(2)
+            .run();
+    });
+    assert!(res.is_err());
+    assert_eq!(*count.lock().unwrap(), 50);
+}
+
+#[test]
+fn try_run_cancels_siblings() {
+    let token = easy_parallel::CancelToken::new();
+    let res = Parallel::new()
+        .each(0..4, |i| {
+            if i == 2 {
+                thread::sleep(std::time::Duration::from_millis(10));
+                return Err(i);
+            }
+            while !token.is_cancelled() {
+                thread::yield_now();
+            }
+            Err(100 + i)
+        })
+        .try_run_with(&token);
+    assert_eq!(res, Err::<Vec<()>, _>(2));
+
+    let ok = Parallel::new().each(0..3, Ok::<_, ()>).try_run();
+    assert_eq!(ok, Ok(vec![0, 1, 2]));
+
+    // Queued closures don't start after a failure.
+    let started = std::sync::atomic::AtomicUsize::new(0);
+    let res = Parallel::new()
+        .each(0..10, |i| {
+            started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if i == 0 {
+                return Err(i);
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+            Ok(i)
+        })
+        .limit(1)
+        .try_run();
+    assert_eq!(res, Err(0));
+    assert!(started.load(std::sync::atomic::Ordering::SeqCst) < 10);
+
+    // Cancelling the token from outside doesn't skip closures.
+    let token = easy_parallel::CancelToken::new();
+    token.cancel();
+    let ok = Parallel::new()
+        .each(0..3, Ok::<_, ()>)
+        .limit(1)
+        .try_run_with(&token);
+    assert_eq!(ok, Ok(vec![0, 1, 2]));
+}
+
+#[test]
+fn run_catch_keeps_other_results() {
+    let res = Parallel::new()
+        .each(0..5, |i| {
+            if i % 2 == 1 {
+                panic!("odd");
+            }
+            i
+        })
+        .run_catch();
+    let ok = res
+        .iter()
+        .map(|r| r.as_ref().ok().copied())
+        .collect::<Vec<_>>();
+    assert_eq!(ok, [Some(0), None, Some(2), None, Some(4)]);
+
+    let (res, main) = Parallel::new()
+        .add(|| 1)
+        .finish_catch(|| -> () { panic!("main") });
+    assert_eq!(res.len(), 1);
+    assert!(res[0].is_ok());
+    assert!(main.is_err());
+}
+
+#[test]
+fn finish_streaming_in_completion_order() {
+    let order = Parallel::new()
+        .each(0..3u64, |i| {
+            thread::sleep(std::time::Duration::from_millis(100 * (3 - i)));
+            i
+        })
+        .add(|| 9)
+        .finish_streaming(|results| results.collect::<Vec<_>>());
+    assert_eq!(order.len(), 4);
+    assert_eq!(order[0], 9);
+    assert_eq!(
+        order.into_iter().collect::<BTreeSet<_>>(),
+        [0, 1, 2, 9].into()
+    );
+
+    let res = panic::catch_unwind(|| {
+        Parallel::new()
+            .add(|| 1)
+            .add(|| panic!("boom"))
+            .finish_streaming(|results| assert_eq!(results.collect::<Vec<_>>(), [1]))
+    });
+    assert!(res.is_err());
+
+    // Under a limit, the main closure consumes results while the queue is still being drained.
+    let done = std::sync::atomic::AtomicUsize::new(0);
+    let first = Parallel::new()
+        .each(0..10, |i| {
+            thread::sleep(std::time::Duration::from_millis(10));
+            done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            i
+        })
+        .limit(1)
+        .finish_streaming(|mut results| {
+            results.next();
+            let first = done.load(std::sync::atomic::Ordering::SeqCst);
+            results.for_each(drop);
+            first
+        });
+    assert!(first < 10);
+}
+
+#[test]
+fn finish_streaming_bounded_backpressure() {
+    let started = std::sync::atomic::AtomicUsize::new(0);
+    let res = Parallel::new()
+        .each(0..10, |i| {
+            started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            i
+        })
+        .limit(1)
+        .finish_streaming_bounded(1, |results| {
+            // One result is buffered and the worker is blocked on the next one.
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert!(started.load(std::sync::atomic::Ordering::SeqCst) <= 2);
+            results.collect::<Vec<_>>()
+        });
+    assert_eq!(res, (0..10).collect::<Vec<_>>());
+
+    // Closures running on the current thread before the main closure don't block.
+    let res = Parallel::new()
+        .each(0..5, |i| i)
+        .with_config(&easy_parallel::ParallelConfig::new().max_threads(0))
+        .finish_streaming_bounded(0, |results| results.collect::<BTreeSet<_>>());
+    assert_eq!(res, (0..5).collect());
+}
+
+#[test]
+fn collect_unordered() {
+    let seen = Mutex::new(Vec::new());
+    let res = Parallel::new()
+        .each(0..20, |i| i * 2)
+        .inspect(|index, &t| {
+            assert_eq!(t, index * 2);
+            seen.lock().unwrap().push(index);
+        })
+        .collect_unordered::<BTreeSet<_>>();
+    assert_eq!(res, (0..20).map(|i| i * 2).collect());
+
+    let mut seen = seen.into_inner().unwrap();
+    seen.sort();
+    assert_eq!(seen, (0..20).collect::<Vec<_>>());
+
+    let empty = Parallel::<i32>::new().collect_unordered::<Vec<_>>();
+    assert!(empty.is_empty());
+
+    // Under a limit, results are collected while the queue is still being drained.
+    let done = std::sync::atomic::AtomicUsize::new(0);
+    let first = Mutex::new(None);
+    let res = Parallel::new()
+        .each(0..10, |i| {
+            thread::sleep(std::time::Duration::from_millis(10));
+            done.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            i
+        })
+        .limit(1)
+        .inspect(|_, _| {
+            let mut first = first.lock().unwrap();
+            first.get_or_insert(done.load(std::sync::atomic::Ordering::SeqCst));
+        })
+        .collect_unordered::<BTreeSet<_>>();
+    assert_eq!(res, (0..10).collect());
+    assert!(first.into_inner().unwrap().unwrap() < 10);
+}
+
+#[test]
+fn heterogeneous_joins() {
+    let (a, b) = easy_parallel::join2(|| 1, || "b");
+    assert_eq!((a, b), (1, "b"));
+
+    let data = [1, 2, 3];
+    let (sum, max, len) = easy_parallel::join3(
+        || data.iter().sum::<i32>(),
+        || data.iter().max().copied(),
+        || data.len(),
+    );
+    assert_eq!((sum, max, len), (6, Some(3), 3));
+
+    let res = panic::catch_unwind(|| easy_parallel::join4(|| 1, || -> () { panic!() }, || 3, || 4));
+    assert!(res.is_err());
+}
@@ -0,0 +1,30 @@
+#![cfg(feature = "trace")]
+
+use easy_parallel::Parallel;
+
+#[test]
+fn write_json() {
+    let (res, trace) = Parallel::new().each(0..3, |i| i).run_traced();
+    assert_eq!(res, [0, 1, 2]);
+
+    let mut json = Vec::new();
+    trace.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+
+    assert!(json.starts_with("{\"traceEvents\":[{\"name\":\"task 0\",\"ph\":\"X\""));
+    assert!(json.contains("\"name\":\"task 2\""));
+    assert!(json.contains("\"tid\":0,"));
+    assert!(json.contains("\"args\":{\"location\":\"tests/trace.rs:7:"));
+    assert!(json.contains("\",\"queued\":"));
+    assert!(json.ends_with("}]}"));
+
+    // Closures sharing a thread share a track too.
+    let (_, trace) = Parallel::new().each(0..8, |i| i).limit(1).run_traced();
+    let mut json = Vec::new();
+    trace.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+
+    assert_eq!(json.matches("\"ph\":\"X\"").count(), 8);
+    assert!(json.contains("\"tid\":0"));
+    assert!(!json.contains("\"tid\":2"));
+}
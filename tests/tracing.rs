@@ -0,0 +1,34 @@
+#![cfg(feature = "tracing")]
+
+use easy_parallel::Parallel;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{dispatcher, Event, Metadata, Subscriber};
+
+struct TestSubscriber;
+
+impl Subscriber for TestSubscriber {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+    fn event(&self, _: &Event<'_>) {}
+    fn enter(&self, _: &Id) {}
+    fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn propagate_dispatcher() {
+    let res = tracing::subscriber::with_default(TestSubscriber, || {
+        Parallel::new()
+            .each(0..4, |_| {
+                dispatcher::get_default(|d| d.is::<TestSubscriber>())
+            })
+            .run()
+    });
+
+    assert_eq!(res, [true; 4]);
+}